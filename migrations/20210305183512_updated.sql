@@ -0,0 +1 @@
+ALTER TABLE redirect ADD COLUMN updated TIMESTAMP;
@@ -0,0 +1 @@
+ALTER TABLE redirect ADD COLUMN status INTEGER NOT NULL DEFAULT 307;
@@ -0,0 +1,3 @@
+ALTER TABLE redirect ADD COLUMN prefix TEXT;
+
+CREATE INDEX idx_prefix ON redirect (prefix);
@@ -0,0 +1 @@
+ALTER TABLE redirect ADD COLUMN query_policy TEXT NOT NULL DEFAULT 'drop';
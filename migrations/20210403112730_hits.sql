@@ -0,0 +1,2 @@
+ALTER TABLE redirect ADD COLUMN hits BIGINT NOT NULL DEFAULT 0;
+ALTER TABLE redirect ADD COLUMN last_accessed TIMESTAMP;
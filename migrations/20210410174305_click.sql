@@ -0,0 +1,13 @@
+CREATE TABLE click (
+    path TEXT NOT NULL,
+    created TIMESTAMP NOT NULL,
+    referrer TEXT,
+    user_agent TEXT,
+    ip TEXT,
+    CONSTRAINT fk_path
+        FOREIGN KEY (path)
+        REFERENCES redirect (path)
+        ON DELETE CASCADE
+);
+
+CREATE INDEX idx_click_path_created ON click (path, created);
@@ -0,0 +1,16 @@
+CREATE TABLE token (
+    hash TEXT NOT NULL,
+    "user" TEXT NOT NULL,
+    name TEXT NOT NULL,
+    created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires TIMESTAMP,
+    last_used TIMESTAMP,
+    CONSTRAINT pk_hash
+        PRIMARY KEY (hash),
+    CONSTRAINT uq_user_name
+        UNIQUE ("user", name),
+    CONSTRAINT fk_token_user
+        FOREIGN KEY ("user")
+        REFERENCES "user" (username)
+        ON DELETE CASCADE
+);
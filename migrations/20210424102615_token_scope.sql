@@ -0,0 +1,2 @@
+ALTER TABLE token ADD COLUMN scopes TEXT NOT NULL DEFAULT 'read create update delete admin';
+ALTER TABLE token ADD COLUMN prefix TEXT;
@@ -0,0 +1,23 @@
+CREATE TABLE "group" (
+    name TEXT NOT NULL,
+    created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT pk_group
+        PRIMARY KEY (name)
+);
+
+CREATE TABLE group_member (
+    "group" TEXT NOT NULL,
+    "user" TEXT NOT NULL,
+    CONSTRAINT pk_group_member
+        PRIMARY KEY ("group", "user"),
+    CONSTRAINT fk_member_group
+        FOREIGN KEY ("group")
+        REFERENCES "group" (name)
+        ON DELETE CASCADE,
+    CONSTRAINT fk_member_user
+        FOREIGN KEY ("user")
+        REFERENCES "user" (username)
+        ON DELETE CASCADE
+);
+
+ALTER TABLE redirect ADD COLUMN "group" TEXT REFERENCES "group" (name) ON DELETE SET NULL;
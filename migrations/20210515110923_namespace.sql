@@ -0,0 +1,18 @@
+CREATE TABLE namespace (
+    prefix TEXT NOT NULL,
+    "user" TEXT,
+    "group" TEXT,
+    created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT pk_prefix
+        PRIMARY KEY (prefix),
+    CONSTRAINT fk_namespace_user
+        FOREIGN KEY ("user")
+        REFERENCES "user" (username)
+        ON DELETE CASCADE,
+    CONSTRAINT fk_namespace_group
+        FOREIGN KEY ("group")
+        REFERENCES "group" (name)
+        ON DELETE CASCADE,
+    CONSTRAINT ck_namespace_owner
+        CHECK (("user" IS NULL AND "group" IS NOT NULL) OR ("user" IS NOT NULL AND "group" IS NULL))
+);
@@ -0,0 +1,13 @@
+CREATE TABLE session (
+    hash TEXT NOT NULL,
+    "user" TEXT NOT NULL,
+    csrf TEXT NOT NULL,
+    created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    expires TIMESTAMP NOT NULL,
+    CONSTRAINT pk_session_hash
+        PRIMARY KEY (hash),
+    CONSTRAINT fk_session_user
+        FOREIGN KEY ("user")
+        REFERENCES "user" (username)
+        ON DELETE CASCADE
+);
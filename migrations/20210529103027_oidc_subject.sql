@@ -0,0 +1,12 @@
+CREATE TABLE oidc_subject (
+    issuer TEXT NOT NULL,
+    subject TEXT NOT NULL,
+    "user" TEXT NOT NULL,
+    created TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    CONSTRAINT pk_oidc_subject
+        PRIMARY KEY (issuer, subject),
+    CONSTRAINT fk_oidc_subject_user
+        FOREIGN KEY ("user")
+        REFERENCES "user" (username)
+        ON DELETE CASCADE
+);
@@ -0,0 +1,589 @@
+use base64::decode;
+use log::warn;
+use sqlx::AnyPool;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use warp::{
+    http::{HeaderMap, Method},
+    Filter, Rejection,
+};
+
+use crate::backend::AuthBackend;
+use crate::config::ProxyAuthConfig;
+use crate::error::{ApiError, ApiResult};
+use crate::model::{db, Scope};
+use crate::pattern;
+use crate::session;
+use crate::throttle::Throttle;
+use crate::token;
+
+/// The authenticated user of a request and what the request may do on their behalf.
+#[derive(Debug, Clone)]
+pub struct Identity {
+    pub username: String,
+    admin: bool,
+    scopes: Vec<Scope>,
+    prefix: Option<String>,
+}
+
+impl Identity {
+    /// A user who logged in with their password or through the proxy, which allows
+    /// everything.
+    fn user(user: db::User) -> Self {
+        Identity {
+            username: user.username,
+            admin: user.is_admin,
+            scopes: Scope::ALL.to_vec(),
+            prefix: None,
+        }
+    }
+
+    /// Whether the user may manage the redirects of everyone.
+    pub fn is_admin(&self) -> bool {
+        self.admin
+    }
+
+    pub fn scopes(&self) -> &[Scope] {
+        &self.scopes
+    }
+
+    pub fn prefix(&self) -> Option<&str> {
+        self.prefix.as_deref()
+    }
+
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.contains(&scope)
+    }
+
+    /// Whether `path` is below the prefix the identity is restricted to.
+    pub fn covers(&self, path: &str) -> bool {
+        match &self.prefix {
+            None => true,
+            Some(prefix) => pattern::is_below(path, prefix),
+        }
+    }
+
+    /// Makes sure `path` may be accessed, answers with `403` otherwise.
+    pub fn check_path(&self, path: &str) -> ApiResult<()> {
+        if self.covers(path) {
+            Ok(())
+        } else {
+            Err(ApiError::Forbidden)
+        }
+    }
+}
+
+/// Trusts the username an authenticating reverse proxy puts into a header, but only on
+/// requests coming from the proxy itself.
+#[derive(Clone, Default)]
+pub struct TrustedProxy {
+    config: Option<Arc<ProxyAuthConfig>>,
+}
+
+impl TrustedProxy {
+    pub fn new(config: Option<ProxyAuthConfig>) -> Self {
+        TrustedProxy {
+            config: config.map(Arc::new),
+        }
+    }
+
+    /// The username the proxy vouches for if the request came through it.
+    fn username(&self, addr: Option<IpAddr>, headers: &HeaderMap) -> Option<String> {
+        let config = self.config.as_ref()?;
+        let value = headers.get(config.header.as_str())?;
+
+        if !addr.is_some_and(|addr| config.trusted.iter().any(|net| net.contains(&addr))) {
+            warn!(
+                "ignoring {} header from untrusted {:?}",
+                config.header, addr
+            );
+            return None;
+        }
+
+        value
+            .to_str()
+            .ok()
+            .map(str::trim)
+            .filter(|username| !username.is_empty())
+            .map(str::to_string)
+    }
+}
+
+/// Everything authenticating a request needs, created once on startup and handed to
+/// every route.
+#[derive(Clone)]
+pub struct AuthContext {
+    pub db_pool: AnyPool,
+    pub backend: Arc<dyn AuthBackend>,
+    pub throttle: Throttle,
+    pub proxy: TrustedProxy,
+}
+
+/// Authenticates a request with either a username set by a trusted proxy, basic auth,
+/// an api token sent as `Authorization: Bearer <token>` or a session cookie and makes
+/// sure it is allowed to use `scope`.
+pub fn filter(
+    context: AuthContext,
+    scope: Scope,
+) -> impl Filter<Extract = (Identity,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("Authorization")
+        .and(warp::cookie::optional::<String>(session::COOKIE))
+        .and(warp::header::optional::<String>(session::CSRF_HEADER))
+        .and(warp::method())
+        .and(warp::addr::remote())
+        .and(warp::header::headers_cloned())
+        .and_then(
+            move |header: Option<String>,
+                  cookie: Option<String>,
+                  csrf: Option<String>,
+                  method: Method,
+                  addr: Option<SocketAddr>,
+                  headers: HeaderMap| {
+                let context = context.clone();
+                async move {
+                    let addr = addr.map(|addr| addr.ip());
+                    let db_pool = &context.db_pool;
+                    // the proxy may pass its own credentials on, it already checked them
+                    let identity = match (context.proxy.username(addr, &headers), header, cookie) {
+                        (Some(username), _, _) => {
+                            proxy_auth(db_pool, &username, &headers, &method).await?
+                        }
+                        (None, Some(header), _) => authenticate(&context, addr, header).await?,
+                        (None, None, Some(id)) => session_auth(db_pool, &id, csrf, &method).await?,
+                        // without credentials another route may still match
+                        (None, None, None) => return Err(warp::reject::not_found()),
+                    };
+
+                    if identity.allows(scope) {
+                        Ok(identity)
+                    } else {
+                        Err(Rejection::from(ApiError::Forbidden))
+                    }
+                }
+            },
+        )
+}
+
+/// Like [`filter`], but only lets admins through who may use [`Scope::Admin`].
+pub fn admin_filter(
+    context: AuthContext,
+) -> impl Filter<Extract = (Identity,), Error = Rejection> + Clone {
+    filter(context, Scope::Admin).and_then(|identity: Identity| async move {
+        if identity.is_admin() {
+            Ok(identity)
+        } else {
+            Err(Rejection::from(ApiError::Forbidden))
+        }
+    })
+}
+
+async fn authenticate(
+    context: &AuthContext,
+    addr: Option<IpAddr>,
+    header: String,
+) -> ApiResult<Identity> {
+    if let Some(token) = header.strip_prefix("Bearer ") {
+        return token_auth(&context.db_pool, token.trim()).await;
+    }
+
+    let credentials = header
+        .strip_prefix("Basic ")
+        .and_then(|s| decode(s).ok())
+        .and_then(|vec| String::from_utf8(vec).ok());
+
+    basic_auth(
+        context.db_pool.clone(),
+        context.backend.clone(),
+        &context.throttle,
+        addr,
+        credentials,
+    )
+    .await
+    .map(Identity::user)
+}
+
+/// A browser sends the cookie along with requests other sites trigger, so changes also
+/// need the csrf token of the session, which only our own pages can read.
+async fn session_auth(
+    db_pool: &AnyPool,
+    id: &str,
+    csrf: Option<String>,
+    method: &Method,
+) -> ApiResult<Identity> {
+    let session = session::verify(db_pool, id)
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+
+    let safe = matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS);
+    if !safe && !csrf.is_some_and(|csrf| constant_time_eq(&csrf, &session.csrf)) {
+        return Err(ApiError::Forbidden);
+    }
+
+    Ok(Identity {
+        username: session.user,
+        admin: session.is_admin,
+        scopes: Scope::ALL.to_vec(),
+        prefix: None,
+    })
+}
+
+/// The proxy usually keeps its own login in a cookie, which browsers also send along
+/// with requests other sites trigger. Those are recognized by the `Sec-Fetch-Site`
+/// header browsers add, clients without it aren't browsers.
+async fn proxy_auth(
+    db_pool: &AnyPool,
+    username: &str,
+    headers: &HeaderMap,
+    method: &Method,
+) -> ApiResult<Identity> {
+    let safe = matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS);
+    let site = headers
+        .get("Sec-Fetch-Site")
+        .and_then(|site| site.to_str().ok());
+    if !safe && !matches!(site, None | Some("same-origin") | Some("none")) {
+        return Err(ApiError::Forbidden);
+    }
+
+    provision(db_pool, username).await?;
+
+    let user = sqlx::query_as::<_, db::User>(
+        "SELECT u.username, u.pw_hash, u.is_admin FROM \"user\" u WHERE username = $1",
+    )
+    .bind(username)
+    .fetch_one(db_pool)
+    .await?;
+
+    Ok(Identity::user(user))
+}
+
+/// Creates a user someone else vouched for on their first login. They get no password,
+/// so they can't use basic auth until one is set with `links user passwd`.
+pub async fn provision(db_pool: &AnyPool, username: &str) -> ApiResult<()> {
+    sqlx::query("INSERT INTO \"user\" (username, pw_hash) SELECT $1, '' WHERE NOT EXISTS(SELECT * FROM \"user\" WHERE username = $1)")
+        .bind(username)
+        .execute(db_pool)
+        .await?;
+
+    Ok(())
+}
+
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+async fn token_auth(db_pool: &AnyPool, token: &str) -> ApiResult<Identity> {
+    let grant = token::verify(db_pool, token)
+        .await?
+        .ok_or(ApiError::Unauthorized)?;
+
+    Ok(Identity {
+        username: grant.user,
+        admin: grant.is_admin,
+        scopes: Scope::parse_list(&grant.scopes),
+        prefix: grant.prefix,
+    })
+}
+
+async fn basic_auth(
+    pool: AnyPool,
+    backend: Arc<dyn AuthBackend>,
+    throttle: &Throttle,
+    addr: Option<IpAddr>,
+    header: Option<String>,
+) -> ApiResult<db::User> {
+    let s = header.ok_or(ApiError::AuthHeaderDecode)?;
+
+    let mut it = s.splitn(2, ':');
+
+    match (it.next(), it.next()) {
+        (Some(username), Some(password)) => {
+            check_credentials(&pool, backend.as_ref(), throttle, addr, username, password).await
+        }
+        _ => Err(ApiError::AuthHeaderDecode),
+    }
+}
+
+/// Makes sure `password` is the one of `username`, answers with `401` otherwise and
+/// with `429` if there were too many failed attempts for the user or from `addr`.
+pub async fn check_credentials(
+    pool: &AnyPool,
+    backend: &dyn AuthBackend,
+    throttle: &Throttle,
+    addr: Option<IpAddr>,
+    username: &str,
+    password: &str,
+) -> ApiResult<db::User> {
+    let username = &backend.canonical(username);
+    throttle.check(username, addr)?;
+
+    match backend.verify(pool, username, password).await {
+        Ok(user) => {
+            throttle.succeed(username);
+            Ok(user)
+        }
+        Err(ApiError::Unauthorized) => {
+            throttle.fail(username, addr);
+            Err(ApiError::Unauthorized)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{basic_auth, provision, token_auth};
+    use crate::backend::DbBackend;
+    use crate::config::{HashAlgorithm, HashConfig, ThrottleConfig};
+    use crate::error::ApiError;
+    use crate::model::Scope;
+    use crate::password::Hasher;
+    use crate::throttle::Throttle;
+    use crate::token;
+    use std::sync::Arc;
+
+    const TEST_USER: &str = "test";
+    const TEST_PW: &str = "test123blub";
+    const TEST_PW_HASH: &str = "$2y$12$3lYfycMuf0IGK11QdlEZ6ufujBbJ5IOh4JGw5h9RIcnc1YiQOl5s6";
+
+    async fn init_pools() -> (sqlx::AnyPool, Hasher) {
+        // every connection to an in-memory sqlite database gets its own database
+        let db_pool = sqlx::any::AnyPoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+
+        sqlx::migrate!().run(&db_pool).await.unwrap();
+
+        sqlx::query("INSERT INTO \"user\" (username, pw_hash) VALUES ($1,$2)")
+            .bind(TEST_USER)
+            .bind(TEST_PW_HASH)
+            .execute(&db_pool)
+            .await
+            .unwrap();
+
+        let th_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(2)
+            .build()
+            .unwrap();
+
+        (db_pool, Hasher::new(th_pool, HashConfig::default()))
+    }
+
+    fn throttle() -> Throttle {
+        Throttle::new(ThrottleConfig::default())
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn basic_auth_valid() {
+        let (db, th) = init_pools().await;
+
+        let res = basic_auth(
+            db,
+            Arc::new(DbBackend::new(th)),
+            &throttle(),
+            None,
+            Some(format!("{}:{}", TEST_USER, TEST_PW)),
+        )
+        .await;
+
+        assert!(res.is_ok());
+        assert_eq!("test", &res.unwrap().username);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn basic_auth_rehash() {
+        let (db, _) = init_pools().await;
+        let hasher = Hasher::new(
+            rayon::ThreadPoolBuilder::new().build().unwrap(),
+            HashConfig {
+                algorithm: HashAlgorithm::Argon2id,
+                argon2_memory: 1024,
+                argon2_iterations: 1,
+                ..HashConfig::default()
+            },
+        );
+        let backend = Arc::new(DbBackend::new(hasher));
+        let credentials = || Some(format!("{}:{}", TEST_USER, TEST_PW));
+
+        let res = basic_auth(
+            db.clone(),
+            backend.clone(),
+            &throttle(),
+            None,
+            credentials(),
+        )
+        .await;
+        assert!(res.is_ok());
+
+        let (pw_hash,): (String,) =
+            sqlx::query_as("SELECT pw_hash FROM \"user\" WHERE username = $1")
+                .bind(TEST_USER)
+                .fetch_one(&db)
+                .await
+                .unwrap();
+        assert!(pw_hash.starts_with("$argon2id$"));
+
+        let res = basic_auth(db, backend, &throttle(), None, credentials()).await;
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn basic_auth_invalid_user() {
+        let (db, th) = init_pools().await;
+
+        let res = basic_auth(
+            db,
+            Arc::new(DbBackend::new(th)),
+            &throttle(),
+            None,
+            Some(format!("{}:{}", "not existant", "blub321test")),
+        )
+        .await;
+
+        assert!(matches!(res, Err(ApiError::Unauthorized)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn basic_auth_invalid_user_timing() {
+        let (db, th) = init_pools().await;
+        let backend = Arc::new(DbBackend::new(th));
+        let throttle = throttle();
+        provision(&db, "provisioned").await.unwrap();
+
+        // every failed login checks one hash, whether the user exists or not
+        let usernames = ["not existant", TEST_USER, "provisioned"];
+        for (i, username) in usernames.iter().enumerate() {
+            let res = basic_auth(
+                db.clone(),
+                backend.clone(),
+                &throttle,
+                None,
+                Some(format!("{}:{}", username, "blub321test")),
+            )
+            .await;
+            assert!(matches!(res, Err(ApiError::Unauthorized)), "{}", username);
+            assert_eq!(i + 1, backend.hasher().checked(), "{}", username);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn basic_auth_invalid_pw() {
+        let (db, th) = init_pools().await;
+
+        let mut old = String::from(TEST_PW);
+        let mut new = String::with_capacity(old.capacity());
+
+        while let Some(c) = old.pop() {
+            new.push(c);
+        }
+
+        let res = basic_auth(
+            db,
+            Arc::new(DbBackend::new(th)),
+            &throttle(),
+            None,
+            Some(format!("{}:{}", TEST_USER, new)),
+        )
+        .await;
+
+        assert!(matches!(res, Err(ApiError::Unauthorized)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn basic_auth_invalid_header() {
+        let (db, th) = init_pools().await;
+
+        let res = basic_auth(
+            db,
+            Arc::new(DbBackend::new(th)),
+            &throttle(),
+            None,
+            Some("something is not quite right here...".to_string()),
+        )
+        .await;
+
+        assert!(matches!(res, Err(ApiError::AuthHeaderDecode)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn token_auth_valid() {
+        let (db, _) = init_pools().await;
+
+        let token = token::create(&db, TEST_USER, "ci", None, &Scope::ALL, None)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let identity = token_auth(&db, &token).await.unwrap();
+        assert_eq!("test", &identity.username);
+        assert!(identity.allows(Scope::Admin));
+        assert!(identity.covers("blub"));
+
+        let used = token::find(&db, TEST_USER, "ci").await.unwrap().unwrap();
+        assert!(used.last_used.is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn token_auth_invalid() {
+        let (db, _) = init_pools().await;
+
+        let token = token::create(&db, TEST_USER, "ci", None, &Scope::ALL, None)
+            .await
+            .unwrap()
+            .unwrap();
+
+        let res = token_auth(&db, &token::generate()).await;
+        assert!(matches!(res, Err(ApiError::Unauthorized)));
+
+        assert!(token::revoke(&db, TEST_USER, "ci").await.unwrap());
+        let res = token_auth(&db, &token).await;
+        assert!(matches!(res, Err(ApiError::Unauthorized)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn token_auth_expired() {
+        let (db, _) = init_pools().await;
+
+        let token = token::generate();
+        sqlx::query("INSERT INTO token (hash, \"user\", name, expires) VALUES ($1,$2,'old','2021-01-01 00:00:00')")
+            .bind(token::hash(&token))
+            .bind(TEST_USER)
+            .execute(&db)
+            .await
+            .unwrap();
+
+        let res = token_auth(&db, &token).await;
+        assert!(matches!(res, Err(ApiError::Unauthorized)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn token_auth_scoped() {
+        let (db, _) = init_pools().await;
+
+        let token = token::create(
+            &db,
+            TEST_USER,
+            "deploy",
+            None,
+            &[Scope::Read, Scope::Create],
+            Some("deploy"),
+        )
+        .await
+        .unwrap()
+        .unwrap();
+
+        let identity = token_auth(&db, &token).await.unwrap();
+        assert!(identity.allows(Scope::Create));
+        assert!(!identity.allows(Scope::Delete));
+        assert!(identity.covers("deploy/staging"));
+        assert!(!identity.covers("deployment"));
+        assert!(identity.check_path("blub").is_err());
+    }
+}
@@ -0,0 +1,300 @@
+use async_trait::async_trait;
+use log::warn;
+use sqlx::AnyPool;
+use std::sync::Arc;
+
+use crate::cache::CredentialCache;
+use crate::config::CredentialCacheConfig;
+use crate::error::{ApiError, ApiResult};
+use crate::model::db;
+use crate::password::Hasher;
+
+/// Where passwords are checked, basic auth and browser logins go through it.
+#[async_trait]
+pub trait AuthBackend: Send + Sync {
+    /// The username `username` logs in as, failed logins are counted for it too.
+    fn canonical(&self, username: &str) -> String {
+        username.to_string()
+    }
+
+    /// Makes sure `password` is the one of `username`, answers with `401` otherwise.
+    async fn verify(
+        &self,
+        db_pool: &AnyPool,
+        username: &str,
+        password: &str,
+    ) -> ApiResult<db::User>;
+
+    /// Replaces the password of `username`, which has passed the policy already.
+    async fn set_password(
+        &self,
+        db_pool: &AnyPool,
+        username: &str,
+        password: &str,
+    ) -> ApiResult<()>;
+}
+
+/// Checks passwords against the hashes in the `user` table.
+pub struct DbBackend {
+    hasher: Hasher,
+}
+
+impl DbBackend {
+    pub fn new(hasher: Hasher) -> Self {
+        DbBackend { hasher }
+    }
+
+    #[cfg(test)]
+    pub fn hasher(&self) -> &Hasher {
+        &self.hasher
+    }
+
+    /// Upgrades the hash of `user` to the configured algorithm and cost, which is only
+    /// possible while the password is known.
+    async fn rehash(&self, db_pool: &AnyPool, user: &db::User, password: &str) -> ApiResult<()> {
+        let pw_hash = self.hasher.hash(password.to_string()).await?;
+
+        // unless the password was changed in the meantime
+        sqlx::query("UPDATE \"user\" SET pw_hash = $1 WHERE username = $2 AND pw_hash = $3")
+            .bind(pw_hash)
+            .bind(&user.username)
+            .bind(&user.pw_hash)
+            .execute(db_pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl AuthBackend for DbBackend {
+    async fn verify(
+        &self,
+        db_pool: &AnyPool,
+        username: &str,
+        password: &str,
+    ) -> ApiResult<db::User> {
+        let user = sqlx::query_as::<_, db::User>(
+            "SELECT u.username, u.pw_hash, u.is_admin FROM \"user\" u WHERE username = $1",
+        )
+        .bind(username.to_string())
+        .fetch_optional(db_pool)
+        .await?;
+
+        // users signed up by the directory, a proxy or a provider have no password, they
+        // take as long to turn down as unknown users
+        let user = match user {
+            Some(user) if !user.pw_hash.is_empty() => user,
+            _ => {
+                self.hasher.verify_dummy(password.to_string()).await?;
+                return Err(ApiError::Unauthorized);
+            }
+        };
+
+        if !self
+            .hasher
+            .verify(password.to_string(), user.pw_hash.clone())
+            .await?
+        {
+            return Err(ApiError::Unauthorized);
+        }
+
+        if self.hasher.needs_rehash(&user.pw_hash) {
+            if let Err(e) = self.rehash(db_pool, &user, password).await {
+                warn!("failed to rehash password of {}: {}", user.username, e);
+            }
+        }
+
+        Ok(user)
+    }
+
+    async fn set_password(
+        &self,
+        db_pool: &AnyPool,
+        username: &str,
+        password: &str,
+    ) -> ApiResult<()> {
+        let pw_hash = self.hasher.hash(password.to_string()).await?;
+
+        sqlx::query("UPDATE \"user\" SET pw_hash = $1 WHERE username = $2")
+            .bind(pw_hash)
+            .bind(username)
+            .execute(db_pool)
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Remembers recently verified passwords in front of any backend, so a burst of
+/// requests only pays for one slow verification or directory bind.
+pub struct CachedBackend {
+    backend: Arc<dyn AuthBackend>,
+    cache: CredentialCache,
+}
+
+impl CachedBackend {
+    pub fn new(backend: Arc<dyn AuthBackend>, config: CredentialCacheConfig) -> Self {
+        CachedBackend {
+            backend,
+            cache: CredentialCache::new(config),
+        }
+    }
+}
+
+#[async_trait]
+impl AuthBackend for CachedBackend {
+    fn canonical(&self, username: &str) -> String {
+        self.backend.canonical(username)
+    }
+
+    async fn verify(
+        &self,
+        db_pool: &AnyPool,
+        username: &str,
+        password: &str,
+    ) -> ApiResult<db::User> {
+        let user = sqlx::query_as::<_, db::User>(
+            "SELECT u.username, u.pw_hash, u.is_admin FROM \"user\" u WHERE username = $1",
+        )
+        .bind(username.to_string())
+        .fetch_optional(db_pool)
+        .await?;
+
+        // only as long as the user still has the hash the password was verified against
+        if let Some(user) = user {
+            if self.cache.contains(username, password, &user.pw_hash) {
+                return Ok(user);
+            }
+        }
+
+        let user = self.backend.verify(db_pool, username, password).await?;
+        self.cache.insert(username, password, &user.pw_hash);
+
+        Ok(user)
+    }
+
+    async fn set_password(
+        &self,
+        db_pool: &AnyPool,
+        username: &str,
+        password: &str,
+    ) -> ApiResult<()> {
+        self.backend
+            .set_password(db_pool, username, password)
+            .await?;
+        self.cache.forget(username);
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AuthBackend, CachedBackend};
+    use crate::config::CredentialCacheConfig;
+    use crate::error::{ApiError, ApiResult};
+    use crate::model::db;
+    use async_trait::async_trait;
+    use sqlx::AnyPool;
+    use std::{
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    /// Accepts `blub` for everyone until revoked and counts how often it was asked.
+    #[derive(Default)]
+    struct Counting {
+        verified: AtomicUsize,
+        revoked: AtomicBool,
+    }
+
+    #[async_trait]
+    impl AuthBackend for Counting {
+        async fn verify(
+            &self,
+            db_pool: &AnyPool,
+            username: &str,
+            password: &str,
+        ) -> ApiResult<db::User> {
+            self.verified.fetch_add(1, Ordering::SeqCst);
+
+            if password != "blub" || self.revoked.load(Ordering::SeqCst) {
+                return Err(ApiError::Unauthorized);
+            }
+
+            let user = sqlx::query_as::<_, db::User>(
+                "SELECT u.username, u.pw_hash, u.is_admin FROM \"user\" u WHERE username = $1",
+            )
+            .bind(username)
+            .fetch_one(db_pool)
+            .await?;
+
+            Ok(user)
+        }
+
+        async fn set_password(&self, _: &AnyPool, _: &str, _: &str) -> ApiResult<()> {
+            Ok(())
+        }
+    }
+
+    async fn init_db() -> AnyPool {
+        // every connection to an in-memory sqlite database gets its own database
+        let db = sqlx::any::AnyPoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&db).await.unwrap();
+        sqlx::query("INSERT INTO \"user\" (username, pw_hash) VALUES ('test', '')")
+            .execute(&db)
+            .await
+            .unwrap();
+
+        db
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn cached() {
+        let db = init_db().await;
+        let counting = Arc::new(Counting::default());
+        let backend = CachedBackend::new(counting.clone(), CredentialCacheConfig::default());
+        let verified = || counting.verified.load(Ordering::SeqCst);
+
+        for _ in 0..3 {
+            assert!(backend.verify(&db, "test", "blub").await.is_ok());
+        }
+        assert_eq!(1, verified());
+
+        assert!(backend.verify(&db, "test", "bulb").await.is_err());
+        assert_eq!(2, verified());
+
+        backend.set_password(&db, "test", "new").await.unwrap();
+        assert!(backend.verify(&db, "test", "blub").await.is_ok());
+        assert_eq!(3, verified());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn cached_unchanged_hash() {
+        // like with a directory the hash stays empty, only the ttl ends a revoked password
+        let db = init_db().await;
+        let counting = Arc::new(Counting::default());
+        let config = CredentialCacheConfig {
+            ttl: 1,
+            ..CredentialCacheConfig::default()
+        };
+        let backend = CachedBackend::new(counting.clone(), config);
+
+        assert!(backend.verify(&db, "test", "blub").await.is_ok());
+        counting.revoked.store(true, Ordering::SeqCst);
+        assert!(backend.verify(&db, "test", "blub").await.is_ok());
+        assert_eq!(1, counting.verified.load(Ordering::SeqCst));
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert!(backend.verify(&db, "test", "blub").await.is_err());
+        assert_eq!(2, counting.verified.load(Ordering::SeqCst));
+    }
+}
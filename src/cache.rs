@@ -0,0 +1,162 @@
+use hmac::{Hmac, Mac, NewMac};
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::config::CredentialCacheConfig;
+
+type Key = [u8; 32];
+
+/// Remembers recently verified passwords, so a burst of requests only pays for one slow
+/// verification. Entries are keyed by an HMAC of username and password with a key that
+/// only lives as long as the process, and only count as long as the user still has the
+/// hash the password was verified against.
+pub struct CredentialCache {
+    config: CredentialCacheConfig,
+    secret: Key,
+    entries: Mutex<HashMap<Key, Entry>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    username: String,
+    pw_hash: String,
+    verified: Instant,
+}
+
+impl CredentialCache {
+    pub fn new(config: CredentialCacheConfig) -> Self {
+        let mut secret = Key::default();
+        OsRng.fill_bytes(&mut secret);
+
+        CredentialCache {
+            config,
+            secret,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.config.ttl > 0 && self.config.size > 0
+    }
+
+    fn ttl(&self) -> Duration {
+        Duration::from_secs(self.config.ttl)
+    }
+
+    fn key(&self, username: &str, password: &str) -> Key {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+        // the length keeps "ab" + "c" and "a" + "bc" apart
+        mac.update(&(username.len() as u64).to_be_bytes());
+        mac.update(username.as_bytes());
+        mac.update(password.as_bytes());
+
+        mac.finalize().into_bytes().into()
+    }
+
+    /// Whether `password` was recently verified for `username` while it had `pw_hash`.
+    pub fn contains(&self, username: &str, password: &str, pw_hash: &str) -> bool {
+        if !self.enabled() {
+            return false;
+        }
+
+        let key = self.key(username, password);
+        let mut entries = self.entries.lock().unwrap();
+
+        match entries.get(&key) {
+            Some(entry) if entry.verified.elapsed() < self.ttl() && entry.pw_hash == pw_hash => {
+                true
+            }
+            Some(_) => {
+                entries.remove(&key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    /// Remembers that `password` matches `pw_hash`, the current hash of `username`.
+    pub fn insert(&self, username: &str, password: &str, pw_hash: &str) {
+        if !self.enabled() {
+            return;
+        }
+
+        let key = self.key(username, password);
+        let mut entries = self.entries.lock().unwrap();
+
+        if entries.len() >= self.config.size && !entries.contains_key(&key) {
+            let ttl = self.ttl();
+            entries.retain(|_, entry| entry.verified.elapsed() < ttl);
+        }
+
+        if entries.len() >= self.config.size && !entries.contains_key(&key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.verified)
+                .map(|(key, _)| *key);
+
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+
+        entries.insert(
+            key,
+            Entry {
+                username: username.to_string(),
+                pw_hash: pw_hash.to_string(),
+                verified: Instant::now(),
+            },
+        );
+    }
+
+    /// Forgets every password remembered for `username`.
+    pub fn forget(&self, username: &str) {
+        self.entries
+            .lock()
+            .unwrap()
+            .retain(|_, entry| entry.username != username);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CredentialCache;
+    use crate::config::CredentialCacheConfig;
+
+    #[test]
+    fn credentials() {
+        let cache = CredentialCache::new(CredentialCacheConfig { ttl: 60, size: 2 });
+
+        cache.insert("test", "blub", "hash");
+        assert!(cache.contains("test", "blub", "hash"));
+        assert!(!cache.contains("test", "bulb", "hash"));
+        assert!(!cache.contains("tes", "tblub", "hash"));
+        // the password was changed since
+        assert!(!cache.contains("test", "blub", "new hash"));
+        assert!(!cache.contains("test", "blub", "hash"));
+
+        cache.insert("test", "blub", "hash");
+        cache.insert("other", "blub", "hash");
+        cache.insert("third", "blub", "hash");
+        assert!(!cache.contains("test", "blub", "hash"));
+        assert!(cache.contains("other", "blub", "hash"));
+        assert!(cache.contains("third", "blub", "hash"));
+
+        cache.forget("other");
+        assert!(!cache.contains("other", "blub", "hash"));
+        assert!(cache.contains("third", "blub", "hash"));
+    }
+
+    #[test]
+    fn disabled() {
+        let cache = CredentialCache::new(CredentialCacheConfig { ttl: 0, size: 2 });
+
+        cache.insert("test", "blub", "hash");
+        assert!(!cache.contains("test", "blub", "hash"));
+    }
+}
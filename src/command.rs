@@ -1,12 +1,22 @@
-use bcrypt::{hash, DEFAULT_COST};
-use rayon::ThreadPool;
 use sqlx::{AnyConnection, AnyPool, Connection};
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 use warp::Filter;
 
-use crate::config::{AddConfig, ServerConfig};
+use crate::auth::{AuthContext, TrustedProxy};
+use crate::backend::{AuthBackend, CachedBackend, DbBackend};
+use crate::config::{
+    AddConfig, GroupConfig, NamespaceConfig, NamespaceOwner, OnDelete, ServerConfig, TokenConfig,
+    UserConfig,
+};
 use crate::error::{ApplicationError, Result};
+use crate::ldap::LdapBackend;
+use crate::metrics;
+use crate::model::db;
+use crate::password::{self, Hasher};
 use crate::server;
+use crate::throttle::Throttle;
+use crate::token;
+use crate::tracker::{self, Tracker};
 
 pub fn run(config: &ServerConfig) -> Result<()> {
     let rt = tokio::runtime::Builder::new_multi_thread()
@@ -21,9 +31,17 @@ pub fn run(config: &ServerConfig) -> Result<()> {
         .build()
         .unwrap();
 
-    let th_pool = Arc::new(th_pool);
+    let hasher = Hasher::new(th_pool, config.password_hash.clone());
+    let backend: Arc<dyn AuthBackend> = match &config.ldap {
+        Some(ldap) => Arc::new(LdapBackend::new(ldap.clone())),
+        None => {
+            hasher.init_dummy();
+            Arc::new(DbBackend::new(hasher))
+        }
+    };
+    let backend = Arc::new(CachedBackend::new(backend, config.credential_cache.clone()));
 
-    async fn run(config: &ServerConfig, th_pool: Arc<ThreadPool>) -> Result<()> {
+    async fn run(config: &ServerConfig, backend: Arc<dyn AuthBackend>) -> Result<()> {
         let db_pool = AnyPool::connect(&config.db_conn)
             .await
             .map_err(ApplicationError::from)?;
@@ -33,7 +51,43 @@ pub fn run(config: &ServerConfig) -> Result<()> {
             .await
             .map_err(ApplicationError::from)?;
 
-        let filter = server::filter(db_pool.clone(), th_pool);
+        let (tracker, tracker_handle) =
+            Tracker::new(db_pool.clone(), Duration::from_secs(config.stats_interval));
+
+        let pruner = if config.click_retention > 0 {
+            Some(tokio::spawn(tracker::prune(
+                db_pool.clone(),
+                config.click_retention,
+            )))
+        } else {
+            None
+        };
+
+        let metrics_server = config.metrics_port.map(|port| {
+            let filter = warp::path("metrics")
+                .and(warp::path::end())
+                .and(metrics::filter(db_pool.clone()));
+            let (_, server) =
+                warp::serve(filter).bind_with_graceful_shutdown(([0, 0, 0, 0], port), async {
+                    tokio::signal::ctrl_c().await.ok();
+                });
+            tokio::spawn(server)
+        });
+
+        let context = AuthContext {
+            db_pool: db_pool.clone(),
+            backend,
+            throttle: Throttle::new(config.login_throttle.clone()),
+            proxy: TrustedProxy::new(config.proxy_auth.clone()),
+        };
+
+        let filter = server::filter(
+            context,
+            tracker,
+            config.public_metrics,
+            config.password_policy.clone(),
+            config.session.clone(),
+        );
         let log = warp::log("links::api");
         let filter = filter.with(log);
 
@@ -44,43 +98,44 @@ pub fn run(config: &ServerConfig) -> Result<()> {
 
         server.await;
 
+        if let Some(metrics_server) = metrics_server {
+            metrics_server.await.ok();
+        }
+
+        // the server held the last tracker, wait for the remaining hits to be written
+        tracker_handle.await.ok();
+
+        if let Some(pruner) = pruner {
+            pruner.abort();
+        }
+
         db_pool.close().await;
 
         Ok(())
     }
 
-    rt.block_on(run(config, th_pool))
+    rt.block_on(run(config, backend))
 }
 
 pub fn add_user(config: &AddConfig) -> Result<()> {
-    let rt = tokio::runtime::Builder::new_multi_thread()
-        .worker_threads(1)
-        .enable_all()
-        .build()
-        .unwrap();
-
     let AddConfig::User {
         db_url,
         username,
         password,
+        admin,
+        hash,
     } = config;
 
-    let password_hash = hash(password, DEFAULT_COST)
+    let password_hash = password::hash(hash, password)
         .map_err(|_| ApplicationError::Custom("failed to hash password"))?;
 
-    async fn run(db_url: &str, username: &str, password_hash: &str) -> Result<()> {
-        let mut connection = AnyConnection::connect(db_url)
-            .await
-            .map_err(ApplicationError::from)?;
+    async fn run(db_url: &str, username: &str, password_hash: &str, admin: bool) -> Result<()> {
+        let mut connection = connect(db_url).await?;
 
-        sqlx::migrate!()
-            .run(&mut connection)
-            .await
-            .map_err(ApplicationError::from)?;
-
-        sqlx::query("INSERT INTO \"user\" (username, pw_hash) VALUES ($1,$2)")
+        sqlx::query("INSERT INTO \"user\" (username, pw_hash, is_admin) VALUES ($1,$2,$3)")
             .bind(username)
             .bind(password_hash)
+            .bind(admin)
             .execute(&mut connection)
             .await
             .map_err(ApplicationError::from)?;
@@ -88,5 +143,588 @@ pub fn add_user(config: &AddConfig) -> Result<()> {
         Ok(())
     }
 
-    rt.block_on(run(db_url, username, &password_hash))
+    runtime().block_on(run(db_url, username, &password_hash, *admin))
+}
+
+pub fn token(config: &TokenConfig) -> Result<()> {
+    async fn run(config: &TokenConfig) -> Result<()> {
+        match config {
+            TokenConfig::Create {
+                db_url,
+                username,
+                name,
+                expires_in_days,
+                scopes,
+                prefix,
+            } => {
+                let mut connection = connect(db_url).await?;
+                let token = token::create(
+                    &mut connection,
+                    username,
+                    name,
+                    *expires_in_days,
+                    scopes,
+                    prefix.as_deref(),
+                )
+                .await?
+                .ok_or(ApplicationError::Custom("token already exists"))?;
+
+                println!("{}", token);
+            }
+            TokenConfig::List { db_url, username } => {
+                let mut connection = connect(db_url).await?;
+
+                for token in token::list(&mut connection, username).await? {
+                    println!(
+                        "{}\t{}\tprefix {}\tcreated {}\texpires {}\tlast used {}",
+                        token.name,
+                        token.scopes,
+                        token.prefix.as_deref().unwrap_or("none"),
+                        token.created,
+                        token.expires.as_deref().unwrap_or("never"),
+                        token.last_used.as_deref().unwrap_or("never"),
+                    );
+                }
+            }
+            TokenConfig::Revoke {
+                db_url,
+                username,
+                name,
+            } => {
+                let mut connection = connect(db_url).await?;
+
+                if !token::revoke(&mut connection, username, name).await? {
+                    return Err(ApplicationError::Custom("token not found"));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    runtime().block_on(run(config))
+}
+
+pub fn user(config: &UserConfig) -> Result<()> {
+    async fn run(config: &UserConfig) -> Result<()> {
+        match config {
+            UserConfig::List { db_url } => {
+                let mut connection = connect(db_url).await?;
+                let users: Vec<(String, bool, i64)> = sqlx::query_as(
+                    "SELECT u.username, u.is_admin, COUNT(r.path) FROM \"user\" u LEFT JOIN redirect r ON r.\"user\" = u.username GROUP BY u.username, u.is_admin ORDER BY u.username",
+                )
+                .fetch_all(&mut connection)
+                .await?;
+
+                for (username, admin, redirects) in users {
+                    let role = if admin { "admin" } else { "user" };
+                    println!("{}\t{}\t{} redirects", username, role, redirects);
+                }
+            }
+            UserConfig::Delete {
+                db_url,
+                username,
+                redirects,
+            } => {
+                let mut connection = connect(db_url).await?;
+                let mut tx = connection.begin().await?;
+
+                if !user_exists(&mut tx, username).await? {
+                    return Err(ApplicationError::Custom("user not found"));
+                }
+
+                match redirects {
+                    OnDelete::Refuse => {
+                        let (count,): (i64,) =
+                            sqlx::query_as("SELECT COUNT(*) FROM redirect WHERE \"user\" = $1")
+                                .bind(username)
+                                .fetch_one(&mut tx)
+                                .await?;
+
+                        if count > 0 {
+                            return Err(ApplicationError::Custom(
+                                "user has redirects, use --cascade or --reassign",
+                            ));
+                        }
+                    }
+                    OnDelete::Cascade => (),
+                    OnDelete::Reassign(to) => {
+                        if to == username || !user_exists(&mut tx, to).await? {
+                            return Err(ApplicationError::Custom("invalid user to reassign to"));
+                        }
+
+                        for table in &["redirect", "namespace"] {
+                            sqlx::query(&format!(
+                                "UPDATE {} SET \"user\" = $1 WHERE \"user\" = $2",
+                                table
+                            ))
+                            .bind(to)
+                            .bind(username)
+                            .execute(&mut tx)
+                            .await?;
+                        }
+                    }
+                }
+
+                // redirects and tokens that are left are removed by the foreign keys
+                sqlx::query("DELETE FROM \"user\" WHERE username = $1")
+                    .bind(username)
+                    .execute(&mut tx)
+                    .await?;
+
+                tx.commit().await?;
+            }
+            UserConfig::Passwd {
+                db_url,
+                username,
+                password,
+                hash,
+            } => {
+                let password_hash = password::hash(hash, password)
+                    .map_err(|_| ApplicationError::Custom("failed to hash password"))?;
+                let mut connection = connect(db_url).await?;
+                let mut tx = connection.begin().await?;
+
+                let rows = sqlx::query("UPDATE \"user\" SET pw_hash = $1 WHERE username = $2")
+                    .bind(password_hash)
+                    .bind(username)
+                    .execute(&mut tx)
+                    .await?
+                    .rows_affected();
+
+                if rows != 1 {
+                    return Err(ApplicationError::Custom("user not found"));
+                }
+
+                // whoever knew the old password may have logged in with it
+                sqlx::query("DELETE FROM session WHERE \"user\" = $1")
+                    .bind(username)
+                    .execute(&mut tx)
+                    .await?;
+
+                tx.commit().await?;
+            }
+            UserConfig::Rename {
+                db_url,
+                username,
+                new_name,
+            } => {
+                let mut connection = connect(db_url).await?;
+                let mut tx = connection.begin().await?;
+
+                if user_exists(&mut tx, new_name).await? {
+                    return Err(ApplicationError::Custom("user already exists"));
+                }
+
+                // the foreign keys don't follow a changed username, so the user is copied,
+                // everything they own is moved over and the old one is removed
+                let rows = sqlx::query("INSERT INTO \"user\" (username, pw_hash, is_admin) SELECT $1, pw_hash, is_admin FROM \"user\" WHERE username = $2")
+                    .bind(new_name)
+                    .bind(username)
+                    .execute(&mut tx)
+                    .await?
+                    .rows_affected();
+
+                if rows != 1 {
+                    return Err(ApplicationError::Custom("user not found"));
+                }
+
+                for table in &[
+                    "redirect",
+                    "token",
+                    "group_member",
+                    "namespace",
+                    "oidc_subject",
+                ] {
+                    sqlx::query(&format!(
+                        "UPDATE {} SET \"user\" = $1 WHERE \"user\" = $2",
+                        table
+                    ))
+                    .bind(new_name)
+                    .bind(username)
+                    .execute(&mut tx)
+                    .await?;
+                }
+
+                sqlx::query("DELETE FROM \"user\" WHERE username = $1")
+                    .bind(username)
+                    .execute(&mut tx)
+                    .await?;
+
+                tx.commit().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    runtime().block_on(run(config))
+}
+
+pub fn group(config: &GroupConfig) -> Result<()> {
+    async fn run(config: &GroupConfig) -> Result<()> {
+        match config {
+            GroupConfig::List { db_url } => {
+                let mut connection = connect(db_url).await?;
+                let groups: Vec<(String, Option<String>)> = sqlx::query_as(
+                    "SELECT g.name, m.\"user\" FROM \"group\" g LEFT JOIN group_member m ON m.\"group\" = g.name ORDER BY g.name, m.\"user\"",
+                )
+                .fetch_all(&mut connection)
+                .await?;
+
+                let mut current: Option<(String, Vec<String>)> = None;
+                for (name, member) in groups {
+                    match &mut current {
+                        Some((group, members)) if *group == name => members.extend(member),
+                        _ => {
+                            if let Some((group, members)) = current.take() {
+                                println!("{}\t{}", group, members.join(", "));
+                            }
+                            current = Some((name, member.into_iter().collect()));
+                        }
+                    }
+                }
+                if let Some((group, members)) = current {
+                    println!("{}\t{}", group, members.join(", "));
+                }
+            }
+            GroupConfig::Create { db_url, name } => {
+                let mut connection = connect(db_url).await?;
+
+                sqlx::query("INSERT INTO \"group\" (name) VALUES ($1)")
+                    .bind(name)
+                    .execute(&mut connection)
+                    .await?;
+            }
+            GroupConfig::Delete { db_url, name } => {
+                let mut connection = connect(db_url).await?;
+
+                // the redirects of the group go back to the users who created them
+                let rows = sqlx::query("DELETE FROM \"group\" WHERE name = $1")
+                    .bind(name)
+                    .execute(&mut connection)
+                    .await?
+                    .rows_affected();
+
+                if rows != 1 {
+                    return Err(ApplicationError::Custom("group not found"));
+                }
+            }
+            GroupConfig::Add {
+                db_url,
+                name,
+                username,
+            } => {
+                let mut connection = connect(db_url).await?;
+
+                sqlx::query("INSERT INTO group_member (\"group\", \"user\") VALUES ($1,$2)")
+                    .bind(name)
+                    .bind(username)
+                    .execute(&mut connection)
+                    .await?;
+            }
+            GroupConfig::Remove {
+                db_url,
+                name,
+                username,
+            } => {
+                let mut connection = connect(db_url).await?;
+
+                let rows =
+                    sqlx::query("DELETE FROM group_member WHERE \"group\" = $1 AND \"user\" = $2")
+                        .bind(name)
+                        .bind(username)
+                        .execute(&mut connection)
+                        .await?
+                        .rows_affected();
+
+                if rows != 1 {
+                    return Err(ApplicationError::Custom(
+                        "user is not a member of the group",
+                    ));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    runtime().block_on(run(config))
+}
+
+pub fn namespace(config: &NamespaceConfig) -> Result<()> {
+    async fn run(config: &NamespaceConfig) -> Result<()> {
+        match config {
+            NamespaceConfig::List { db_url } => {
+                let mut connection = connect(db_url).await?;
+                let namespaces = sqlx::query_as::<_, db::Namespace>(
+                    "SELECT prefix, \"user\", \"group\" FROM namespace ORDER BY prefix",
+                )
+                .fetch_all(&mut connection)
+                .await?;
+
+                for namespace in namespaces {
+                    match (namespace.user, namespace.group) {
+                        (Some(user), _) => println!("{}\tuser {}", namespace.prefix, user),
+                        (_, Some(group)) => println!("{}\tgroup {}", namespace.prefix, group),
+                        _ => println!("{}", namespace.prefix),
+                    }
+                }
+            }
+            NamespaceConfig::Add {
+                db_url,
+                prefix,
+                owner,
+            } => {
+                let mut connection = connect(db_url).await?;
+                let (user, group) = match owner {
+                    NamespaceOwner::User(user) => (Some(user), None),
+                    NamespaceOwner::Group(group) => (None, Some(group)),
+                };
+
+                sqlx::query(
+                    "INSERT INTO namespace (prefix, \"user\", \"group\") VALUES ($1,$2,$3)",
+                )
+                .bind(prefix)
+                .bind(user)
+                .bind(group)
+                .execute(&mut connection)
+                .await?;
+            }
+            NamespaceConfig::Remove { db_url, prefix } => {
+                let mut connection = connect(db_url).await?;
+
+                let rows = sqlx::query("DELETE FROM namespace WHERE prefix = $1")
+                    .bind(prefix)
+                    .execute(&mut connection)
+                    .await?
+                    .rows_affected();
+
+                if rows != 1 {
+                    return Err(ApplicationError::Custom("namespace not found"));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    runtime().block_on(run(config))
+}
+
+async fn user_exists(connection: &mut AnyConnection, username: &str) -> Result<bool> {
+    let user: Option<(String,)> =
+        sqlx::query_as("SELECT username FROM \"user\" WHERE username = $1")
+            .bind(username)
+            .fetch_optional(connection)
+            .await?;
+
+    Ok(user.is_some())
+}
+
+/// Runtime for commands that only do a few queries.
+fn runtime() -> tokio::runtime::Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(1)
+        .enable_all()
+        .build()
+        .unwrap()
+}
+
+async fn connect(db_url: &str) -> Result<AnyConnection> {
+    let mut connection = AnyConnection::connect(db_url)
+        .await
+        .map_err(ApplicationError::from)?;
+
+    sqlx::migrate!()
+        .run(&mut connection)
+        .await
+        .map_err(ApplicationError::from)?;
+
+    Ok(connection)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{connect, runtime, user};
+    use crate::config::{HashConfig, OnDelete, UserConfig};
+
+    /// A fresh sqlite database in a file, the commands open connections of their own.
+    fn init_db(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("links-{}-{}.db", name, std::process::id()));
+        std::fs::remove_file(&path).ok();
+        let db_url = format!("sqlite://{}?mode=rwc", path.display());
+
+        execute(
+            &db_url,
+            &[
+                "INSERT INTO \"user\" (username, pw_hash) VALUES ('alice', 'x'), ('bob', 'x')",
+                "INSERT INTO \"group\" (name) VALUES ('team')",
+                "INSERT INTO group_member (\"group\", \"user\") VALUES ('team', 'alice')",
+                "INSERT INTO namespace (prefix, \"user\") VALUES ('alice', 'alice')",
+                "INSERT INTO oidc_subject (issuer, subject, \"user\") VALUES ('https://id.example.com', '4711', 'alice')",
+                "INSERT INTO redirect (\"user\", path, url) VALUES ('alice', 'a', 'https://example.com/'), ('bob', 'b', 'https://example.org/')",
+            ],
+        );
+
+        db_url
+    }
+
+    fn execute(db_url: &str, statements: &[&str]) {
+        runtime().block_on(async {
+            let mut connection = connect(db_url).await.unwrap();
+            for statement in statements {
+                sqlx::query(statement)
+                    .execute(&mut connection)
+                    .await
+                    .unwrap();
+            }
+        })
+    }
+
+    fn select(db_url: &str, sql: &str) -> Vec<String> {
+        runtime().block_on(async {
+            let mut connection = connect(db_url).await.unwrap();
+            sqlx::query_as::<_, (String,)>(sql)
+                .fetch_all(&mut connection)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|(value,)| value)
+                .collect()
+        })
+    }
+
+    #[test]
+    fn delete_cascade() {
+        let db_url = init_db("delete-cascade");
+
+        let res = user(&UserConfig::Delete {
+            db_url: db_url.clone(),
+            username: "alice".to_string(),
+            redirects: OnDelete::Refuse,
+        });
+        assert!(res.is_err());
+
+        user(&UserConfig::Delete {
+            db_url: db_url.clone(),
+            username: "alice".to_string(),
+            redirects: OnDelete::Cascade,
+        })
+        .unwrap();
+
+        assert_eq!(
+            vec!["bob"],
+            select(&db_url, "SELECT username FROM \"user\"")
+        );
+        assert_eq!(vec!["b"], select(&db_url, "SELECT path FROM redirect"));
+        assert!(select(&db_url, "SELECT \"user\" FROM group_member").is_empty());
+        assert!(select(&db_url, "SELECT \"user\" FROM namespace").is_empty());
+    }
+
+    #[test]
+    fn delete_reassign() {
+        let db_url = init_db("delete-reassign");
+
+        for to in &["alice", "carol"] {
+            let res = user(&UserConfig::Delete {
+                db_url: db_url.clone(),
+                username: "alice".to_string(),
+                redirects: OnDelete::Reassign(to.to_string()),
+            });
+            assert!(res.is_err(), "{}", to);
+        }
+
+        user(&UserConfig::Delete {
+            db_url: db_url.clone(),
+            username: "alice".to_string(),
+            redirects: OnDelete::Reassign("bob".to_string()),
+        })
+        .unwrap();
+
+        assert_eq!(
+            vec!["bob", "bob"],
+            select(&db_url, "SELECT \"user\" FROM redirect ORDER BY path")
+        );
+        assert_eq!(
+            vec!["bob"],
+            select(&db_url, "SELECT \"user\" FROM namespace")
+        );
+    }
+
+    #[test]
+    fn rename() {
+        let db_url = init_db("rename");
+
+        let res = user(&UserConfig::Rename {
+            db_url: db_url.clone(),
+            username: "alice".to_string(),
+            new_name: "bob".to_string(),
+        });
+        assert!(res.is_err());
+
+        user(&UserConfig::Rename {
+            db_url: db_url.clone(),
+            username: "alice".to_string(),
+            new_name: "carol".to_string(),
+        })
+        .unwrap();
+
+        assert_eq!(
+            vec!["bob", "carol"],
+            select(&db_url, "SELECT username FROM \"user\" ORDER BY username")
+        );
+        assert_eq!(
+            vec!["carol"],
+            select(&db_url, "SELECT \"user\" FROM redirect WHERE path = 'a'")
+        );
+        assert_eq!(
+            vec!["carol"],
+            select(&db_url, "SELECT \"user\" FROM group_member")
+        );
+        assert_eq!(
+            vec!["carol"],
+            select(&db_url, "SELECT \"user\" FROM namespace")
+        );
+        assert_eq!(
+            vec!["carol"],
+            select(&db_url, "SELECT \"user\" FROM oidc_subject")
+        );
+    }
+
+    #[test]
+    fn passwd() {
+        let db_url = init_db("passwd");
+        execute(
+            &db_url,
+            &["INSERT INTO session (hash, \"user\", csrf, expires) VALUES ('a', 'alice', 'x', '2099-01-01 00:00:00'), ('b', 'bob', 'x', '2099-01-01 00:00:00')"],
+        );
+        let hash = HashConfig {
+            bcrypt_cost: 4,
+            ..HashConfig::default()
+        };
+
+        let res = user(&UserConfig::Passwd {
+            db_url: db_url.clone(),
+            username: "carol".to_string(),
+            password: "carol123blub".to_string(),
+            hash: hash.clone(),
+        });
+        assert!(res.is_err());
+
+        user(&UserConfig::Passwd {
+            db_url: db_url.clone(),
+            username: "alice".to_string(),
+            password: "alice123blub".to_string(),
+            hash,
+        })
+        .unwrap();
+
+        let pw_hash = select(
+            &db_url,
+            "SELECT pw_hash FROM \"user\" WHERE username = 'alice'",
+        );
+        assert!(bcrypt::verify("alice123blub", &pw_hash[0]).unwrap());
+        assert_eq!(vec!["bob"], select(&db_url, "SELECT \"user\" FROM session"));
+    }
 }
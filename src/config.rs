@@ -1,9 +1,192 @@
+use ipnet::IpNet;
+use std::str::FromStr;
+
+use crate::model::Scope;
+
 pub struct ServerConfig {
     pub async_threads: usize,
     pub blocking_threads: usize,
     pub auth_threads: usize,
     pub port: u16,
     pub db_conn: String,
+    /// Seconds between writing collected hits to the database.
+    pub stats_interval: u64,
+    /// Days clicks are kept for analytics, `0` keeps them forever.
+    pub click_retention: u64,
+    /// Serve metrics at `/metrics` on a port of their own.
+    pub metrics_port: Option<u16>,
+    /// Also serve metrics at `/metrics` on the public port.
+    pub public_metrics: bool,
+    pub password_policy: PasswordPolicy,
+    pub login_throttle: ThrottleConfig,
+    pub password_hash: HashConfig,
+    pub credential_cache: CredentialCacheConfig,
+    pub session: SessionConfig,
+    /// Trust the username an authenticating reverse proxy puts into a header.
+    pub proxy_auth: Option<ProxyAuthConfig>,
+    /// Check passwords with an LDAP directory instead of the hashes in the database.
+    pub ldap: Option<LdapConfig>,
+}
+
+/// Requirements for passwords users set themselves.
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    pub min_length: usize,
+    /// How many of lowercase and uppercase letters, digits and other characters a
+    /// password has to contain.
+    pub min_classes: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length: 8,
+            min_classes: 2,
+        }
+    }
+}
+
+/// How failed logins slow down further attempts.
+#[derive(Debug, Clone)]
+pub struct ThrottleConfig {
+    /// Failed logins per user before they have to wait, `0` disables the throttle.
+    pub free_attempts: u32,
+    /// Failed logins per client address before it has to wait, `0` only limits users.
+    /// Behind a proxy every client has the address of the proxy.
+    pub client_attempts: u32,
+    /// Seconds to wait after the first failure beyond the free ones, doubled with every
+    /// further one.
+    pub base_delay: u64,
+    /// Longest wait in seconds.
+    pub max_delay: u64,
+}
+
+impl Default for ThrottleConfig {
+    fn default() -> Self {
+        ThrottleConfig {
+            free_attempts: 5,
+            client_attempts: 5,
+            base_delay: 1,
+            max_delay: 15 * 60,
+        }
+    }
+}
+
+/// How new password hashes are created.
+#[derive(Debug, Clone)]
+pub struct HashConfig {
+    pub algorithm: HashAlgorithm,
+    pub bcrypt_cost: u32,
+    /// Memory used by argon2id in KiB.
+    pub argon2_memory: u32,
+    pub argon2_iterations: u32,
+}
+
+impl Default for HashConfig {
+    fn default() -> Self {
+        HashConfig {
+            algorithm: HashAlgorithm::Bcrypt,
+            bcrypt_cost: bcrypt::DEFAULT_COST,
+            argon2_memory: 19 * 1024,
+            argon2_iterations: 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Bcrypt,
+    Argon2id,
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bcrypt" => Ok(HashAlgorithm::Bcrypt),
+            "argon2id" => Ok(HashAlgorithm::Argon2id),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How long and how many verified passwords are remembered.
+#[derive(Debug, Clone)]
+pub struct CredentialCacheConfig {
+    /// Seconds a verified password is remembered, `0` disables the cache.
+    pub ttl: u64,
+    /// Most passwords remembered at once.
+    pub size: usize,
+}
+
+impl CredentialCacheConfig {
+    /// Default ttl with an LDAP directory, which changes and revokes passwords without
+    /// links noticing.
+    pub const LDAP_TTL: u64 = 5;
+}
+
+impl Default for CredentialCacheConfig {
+    fn default() -> Self {
+        CredentialCacheConfig {
+            ttl: 60,
+            size: 1024,
+        }
+    }
+}
+
+/// Sessions of users logged in with a browser.
+#[derive(Debug, Clone)]
+pub struct SessionConfig {
+    /// Hours until a session ends.
+    pub ttl_hours: u32,
+    /// Only send the session cookie over https.
+    pub secure_cookie: bool,
+    /// Also start sessions by logging in with an OpenID Connect provider.
+    pub oidc: Option<OidcConfig>,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        SessionConfig {
+            ttl_hours: 24,
+            secure_cookie: false,
+            oidc: None,
+        }
+    }
+}
+
+/// The OpenID Connect provider browsers can log in with and how links is registered
+/// there.
+#[derive(Debug, Clone)]
+pub struct OidcConfig {
+    /// Url the provider's `/.well-known/openid-configuration` is found below.
+    pub issuer: String,
+    pub client_id: String,
+    pub client_secret: String,
+    /// Where the provider sends browsers back to, `/_api/oidc/callback` of this server.
+    pub redirect_uri: String,
+    /// Claim of the id token used as username.
+    pub username_claim: String,
+}
+
+/// The header an authenticating reverse proxy sets and where the proxy runs.
+#[derive(Debug, Clone)]
+pub struct ProxyAuthConfig {
+    /// Header holding the username, e.g. `X-Forwarded-User`.
+    pub header: String,
+    /// Networks requests have to come from for the header to be trusted.
+    pub trusted: Vec<IpNet>,
+}
+
+/// The LDAP directory users bind to with their password.
+#[derive(Debug, Clone)]
+pub struct LdapConfig {
+    /// e.g. `ldaps://ldap.example.com`.
+    pub url: String,
+    /// DN users bind as, `{}` is replaced by the escaped username, e.g.
+    /// `uid={},ou=people,dc=example,dc=com`.
+    pub user_dn: String,
 }
 
 impl Default for ServerConfig {
@@ -14,6 +197,17 @@ impl Default for ServerConfig {
             auth_threads: 4,
             port: 5000,
             db_conn: "sqlite::memory:".to_string(),
+            stats_interval: 10,
+            click_retention: 90,
+            metrics_port: None,
+            public_metrics: false,
+            password_policy: PasswordPolicy::default(),
+            login_throttle: ThrottleConfig::default(),
+            password_hash: HashConfig::default(),
+            credential_cache: CredentialCacheConfig::default(),
+            session: SessionConfig::default(),
+            proxy_auth: None,
+            ldap: None,
         }
     }
 }
@@ -23,5 +217,101 @@ pub enum AddConfig {
         db_url: String,
         username: String,
         password: String,
+        admin: bool,
+        hash: HashConfig,
     },
 }
+
+pub enum TokenConfig {
+    Create {
+        db_url: String,
+        username: String,
+        name: String,
+        expires_in_days: Option<u32>,
+        scopes: Vec<Scope>,
+        prefix: Option<String>,
+    },
+    List {
+        db_url: String,
+        username: String,
+    },
+    Revoke {
+        db_url: String,
+        username: String,
+        name: String,
+    },
+}
+
+pub enum UserConfig {
+    List {
+        db_url: String,
+    },
+    Delete {
+        db_url: String,
+        username: String,
+        redirects: OnDelete,
+    },
+    Passwd {
+        db_url: String,
+        username: String,
+        password: String,
+        hash: HashConfig,
+    },
+    Rename {
+        db_url: String,
+        username: String,
+        new_name: String,
+    },
+}
+
+/// What happens to the redirects of a deleted user.
+pub enum OnDelete {
+    /// Only delete users without redirects.
+    Refuse,
+    Cascade,
+    Reassign(String),
+}
+
+pub enum GroupConfig {
+    List {
+        db_url: String,
+    },
+    Create {
+        db_url: String,
+        name: String,
+    },
+    Delete {
+        db_url: String,
+        name: String,
+    },
+    Add {
+        db_url: String,
+        name: String,
+        username: String,
+    },
+    Remove {
+        db_url: String,
+        name: String,
+        username: String,
+    },
+}
+
+pub enum NamespaceConfig {
+    List {
+        db_url: String,
+    },
+    Add {
+        db_url: String,
+        prefix: String,
+        owner: NamespaceOwner,
+    },
+    Remove {
+        db_url: String,
+        prefix: String,
+    },
+}
+
+pub enum NamespaceOwner {
+    User(String),
+    Group(String),
+}
@@ -1,5 +1,9 @@
 use serde::{ser::SerializeMap, Serialize};
-use warp::{hyper::StatusCode, reject::Reject};
+use warp::{
+    http::header::{HeaderValue, RETRY_AFTER},
+    hyper::StatusCode,
+    reject::Reject,
+};
 
 pub type Result<T> = std::result::Result<T, ApplicationError>;
 pub type ApiResult<T> = std::result::Result<T, ApiError>;
@@ -22,6 +26,8 @@ pub enum ApplicationError {
 pub enum ApiError {
     #[error("unauthorized")]
     Unauthorized,
+    #[error("forbidden")]
+    Forbidden,
     #[error("failed to decode auth header")]
     AuthHeaderDecode,
     #[error("{0}")]
@@ -34,6 +40,28 @@ pub enum ApiError {
     NotFound,
     #[error("invalid uri {0}")]
     InvalidUri(String),
+    #[error("path {0} is reserved")]
+    ReservedPath(String),
+    #[error("invalid path pattern {0}")]
+    InvalidPattern(String),
+    #[error("placeholder {{{0}}} is not part of the path")]
+    UnknownPlaceholder(String),
+    #[error("token {0} already exists")]
+    TokenAlreadyExists(String),
+    #[error("invalid token name {0}")]
+    InvalidTokenName(String),
+    #[error("{0}")]
+    WeakPassword(&'static str),
+    #[error("user {0} doesn't exist")]
+    UnknownUser(String),
+    #[error("namespace {0} is reserved")]
+    ReservedNamespace(String),
+    #[error("too many failed logins, retry in {0} seconds")]
+    TooManyAttempts(u64),
+    #[error("openid provider failed: {0}")]
+    Oidc(String),
+    #[error("ldap directory failed: {0}")]
+    Ldap(String),
 }
 
 impl Reject for ApiError {}
@@ -60,9 +88,20 @@ impl From<&ApiError> for ApiErrorMessage {
     fn from(api_error: &ApiError) -> Self {
         let code = match api_error {
             ApiError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden | ApiError::ReservedNamespace(_) => StatusCode::FORBIDDEN,
             ApiError::AuthHeaderDecode => StatusCode::BAD_REQUEST,
             ApiError::NotFound => StatusCode::NOT_FOUND,
-            ApiError::PathAlreadyExists(_) => StatusCode::CONFLICT,
+            ApiError::PathAlreadyExists(_) | ApiError::TokenAlreadyExists(_) => {
+                StatusCode::CONFLICT
+            }
+            ApiError::ReservedPath(_)
+            | ApiError::InvalidPattern(_)
+            | ApiError::UnknownPlaceholder(_)
+            | ApiError::InvalidTokenName(_)
+            | ApiError::WeakPassword(_)
+            | ApiError::UnknownUser(_) => StatusCode::BAD_REQUEST,
+            ApiError::TooManyAttempts(_) => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::Oidc(_) | ApiError::Ldap(_) => StatusCode::BAD_GATEWAY,
             _ => StatusCode::INTERNAL_SERVER_ERROR,
         };
 
@@ -82,10 +121,20 @@ impl From<&ApiError> for ApiErrorMessage {
 impl warp::Reply for &ApiError {
     fn into_response(self) -> warp::reply::Response {
         let msg = ApiErrorMessage::from(self);
-        warp::reply::with_status(warp::reply::json(&msg), msg.status_code).into_response()
+        let mut response =
+            warp::reply::with_status(warp::reply::json(&msg), msg.status_code).into_response();
+
+        if let ApiError::TooManyAttempts(secs) = self {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(*secs));
+        }
+
+        response
     }
 }
 
+#[allow(dead_code)]
 trait ErrorCode {
     fn error_code(&self) -> u16;
 }
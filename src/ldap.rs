@@ -0,0 +1,249 @@
+use async_trait::async_trait;
+use ldap3::{dn_escape, LdapConnAsync, LdapConnSettings};
+use sqlx::AnyPool;
+use std::time::Duration;
+
+use crate::auth;
+use crate::backend::AuthBackend;
+use crate::config::LdapConfig;
+use crate::error::{ApiError, ApiResult};
+use crate::model::db;
+
+/// Seconds to wait for the directory to connect or answer.
+const TIMEOUT_SECS: u64 = 10;
+/// Result code of a bind with a wrong password or an unknown DN.
+const INVALID_CREDENTIALS: u32 = 49;
+
+/// Checks passwords by binding to an LDAP directory as the user. Only the directory
+/// knows the passwords, users are created in the database on their first login.
+pub struct LdapBackend {
+    config: LdapConfig,
+}
+
+fn ldap_error(e: ldap3::LdapError) -> ApiError {
+    ApiError::Ldap(e.to_string())
+}
+
+impl LdapBackend {
+    pub fn new(config: LdapConfig) -> Self {
+        LdapBackend { config }
+    }
+
+    fn user_dn(&self, username: &str) -> String {
+        self.config.user_dn.replace("{}", &dn_escape(username))
+    }
+
+    /// Whether the directory accepts `password` for `username`.
+    async fn bind(&self, username: &str, password: &str) -> ApiResult<bool> {
+        let timeout = Duration::from_secs(TIMEOUT_SECS);
+        let settings = LdapConnSettings::new().set_conn_timeout(timeout);
+        let (conn, mut ldap) = LdapConnAsync::with_settings(settings, &self.config.url)
+            .await
+            .map_err(ldap_error)?;
+        ldap3::drive!(conn);
+
+        let result = ldap
+            .with_timeout(timeout)
+            .simple_bind(&self.user_dn(username), password)
+            .await
+            .map_err(ldap_error)?;
+        ldap.unbind().await.ok();
+
+        match result.rc {
+            0 => Ok(true),
+            INVALID_CREDENTIALS => Ok(false),
+            _ => Err(ApiError::Ldap(result.to_string())),
+        }
+    }
+}
+
+#[async_trait]
+impl AuthBackend for LdapBackend {
+    /// Directories match uids regardless of case, `Alice` binds as `alice` does.
+    fn canonical(&self, username: &str) -> String {
+        username.to_lowercase()
+    }
+
+    async fn verify(
+        &self,
+        db_pool: &AnyPool,
+        username: &str,
+        password: &str,
+    ) -> ApiResult<db::User> {
+        // without a password the bind is anonymous, which most directories allow
+        if password.is_empty() || !self.bind(username, password).await? {
+            return Err(ApiError::Unauthorized);
+        }
+
+        auth::provision(db_pool, username).await?;
+
+        let user = sqlx::query_as::<_, db::User>(
+            "SELECT u.username, u.pw_hash, u.is_admin FROM \"user\" u WHERE username = $1",
+        )
+        .bind(username)
+        .fetch_one(db_pool)
+        .await?;
+
+        Ok(user)
+    }
+
+    /// Passwords are changed in the directory.
+    async fn set_password(&self, _: &AnyPool, _: &str, _: &str) -> ApiResult<()> {
+        Err(ApiError::Forbidden)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LdapBackend;
+    use crate::backend::AuthBackend;
+    use crate::config::LdapConfig;
+    use crate::error::ApiError;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    const ALICE_DN: &str = "uid=alice,ou=people,dc=example,dc=com";
+    const ALICE_PW: &str = "alice123blub";
+
+    async fn init_db() -> sqlx::AnyPool {
+        // every connection to an in-memory sqlite database gets its own database
+        let db_pool = sqlx::any::AnyPoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+
+        sqlx::migrate!().run(&db_pool).await.unwrap();
+
+        db_pool
+    }
+
+    fn backend(url: String) -> LdapBackend {
+        LdapBackend::new(LdapConfig {
+            url,
+            user_dn: "uid={},ou=people,dc=example,dc=com".to_string(),
+        })
+    }
+
+    /// Splits the first BER element off `buf`, returns its tag, its content and its
+    /// length including the header.
+    fn element(buf: &[u8]) -> Option<(u8, &[u8], usize)> {
+        let tag = *buf.first()?;
+        let first = usize::from(*buf.get(1)?);
+        let (len, start) = if first < 0x80 {
+            (first, 2)
+        } else {
+            let n = first & 0x7f;
+            let len = buf
+                .get(2..2 + n)?
+                .iter()
+                .fold(0, |len, b| len << 8 | usize::from(*b));
+            (len, 2 + n)
+        };
+
+        Some((tag, buf.get(start..start + len)?, start + len))
+    }
+
+    /// Starts a directory that only knows simple binds, accepts alice and like most
+    /// directories anonymous binds without a password.
+    async fn stand_in() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = Vec::new();
+                    let mut chunk = [0; 1024];
+
+                    while let Ok(read @ 1..) = socket.read(&mut chunk).await {
+                        buf.extend_from_slice(&chunk[..read]);
+
+                        while let Some((_, message, len)) = element(&buf) {
+                            let (_, _, id_len) = element(message).unwrap();
+                            let (op, request, _) = element(&message[id_len..]).unwrap();
+                            // anything but a bind ends the connection
+                            if op != 0x60 {
+                                return;
+                            }
+
+                            let (_, _, version_len) = element(request).unwrap();
+                            let (_, dn, dn_len) = element(&request[version_len..]).unwrap();
+                            let (_, password, _) =
+                                element(&request[version_len + dn_len..]).unwrap();
+                            let rc = if password.is_empty()
+                                || (dn == ALICE_DN.as_bytes() && password == ALICE_PW.as_bytes())
+                            {
+                                0
+                            } else {
+                                49
+                            };
+
+                            let result = [0x0a, 1, rc, 0x04, 0, 0x04, 0];
+                            let mut response = vec![0x30, (id_len + 2 + result.len()) as u8];
+                            response.extend_from_slice(&message[..id_len]);
+                            response.extend_from_slice(&[0x61, result.len() as u8]);
+                            response.extend_from_slice(&result);
+                            socket.write_all(&response).await.unwrap();
+
+                            buf.drain(..len);
+                        }
+                    }
+                });
+            }
+        });
+
+        format!("ldap://{}", addr)
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn bind() {
+        let db = init_db().await;
+        let backend = backend(stand_in().await);
+        assert_eq!("alice", backend.canonical("Alice"));
+
+        let user = backend.verify(&db, "alice", ALICE_PW).await.unwrap();
+        assert_eq!("alice", user.username);
+        assert!(!user.is_admin);
+
+        // the second login finds the user created by the first
+        assert!(backend.verify(&db, "alice", ALICE_PW).await.is_ok());
+
+        for (username, password) in &[("alice", "wrong"), ("bob", ALICE_PW), ("alice", "")] {
+            let res = backend.verify(&db, username, password).await;
+            assert!(matches!(res, Err(ApiError::Unauthorized)), "{}", username);
+        }
+
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM \"user\"")
+            .fetch_one(&db)
+            .await
+            .unwrap();
+        assert_eq!(1, count);
+
+        let res = backend.set_password(&db, "alice", "new123blub").await;
+        assert!(matches!(res, Err(ApiError::Forbidden)));
+    }
+
+    #[test]
+    fn user_dn() {
+        let backend = backend("ldap://localhost".to_string());
+
+        assert_eq!(
+            "uid=alice\\2cou\\3dadmins,ou=people,dc=example,dc=com",
+            backend.user_dn("alice,ou=admins")
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn unreachable() {
+        let db = init_db().await;
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("ldap://{}", listener.local_addr().unwrap());
+        drop(listener);
+
+        let res = backend(url).verify(&db, "alice", ALICE_PW).await;
+        assert!(matches!(res, Err(ApiError::Ldap(_))));
+    }
+}
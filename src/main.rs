@@ -1,19 +1,38 @@
 use clap::{clap_app, ArgMatches};
 use dotenv::dotenv;
+use ipnet::IpNet;
 use log::error;
-use shadow_rs::shadow;
 use std::process::exit;
-use std::{env, str::FromStr};
+use std::{env, net::IpAddr, str::FromStr};
 
 use error::{ApplicationError, Result};
 
-shadow!(build);
+// the code shadow-rs generates separates its header comment with empty lines
+#[allow(clippy::empty_line_after_doc_comments)]
+mod generated {
+    use shadow_rs::shadow;
 
+    shadow!(build);
+}
+use generated::build;
+
+mod auth;
+mod backend;
+mod cache;
 mod command;
 mod config;
 mod error;
+mod ldap;
+mod metrics;
 mod model;
+mod oidc;
+mod password;
+mod pattern;
 mod server;
+mod session;
+mod throttle;
+mod token;
+mod tracker;
 
 fn main() {
     dotenv().ok();
@@ -35,21 +54,146 @@ fn main() {
             (@arg AUTH_THREADS: --auth +takes_value "number of threads used to validate passwords, defaults to 4")
             (@arg SYNC_THREADS: --sync +takes_value "number of max sync worker, defaults to 128")
             (@arg CONNECTION: -c --connection +takes_value "database connection string, defaults to 'sqlite::memory:'")
+            (@arg STATS_INTERVAL: --("stats-interval") +takes_value "seconds between writing hit counts to the database, defaults to 10")
+            (@arg METRICS_PORT: --("metrics-port") +takes_value "serve metrics at /metrics on this port")
+            (@arg PUBLIC_METRICS: --("public-metrics") "also serve metrics at /metrics on the public port")
+            (@arg CLICK_RETENTION: --("click-retention") +takes_value "days clicks are kept for analytics, 0 keeps them forever, defaults to 90")
+            (@arg PASSWORD_MIN_LENGTH: --("password-min-length") +takes_value "minimum length of passwords set over http, defaults to 8")
+            (@arg PASSWORD_MIN_CLASSES: --("password-min-classes") +takes_value "how many of lowercase, uppercase, digits and symbols passwords set over http need, defaults to 2")
+            (@arg LOGIN_ATTEMPTS: --("login-attempts") +takes_value "failed logins per user before they have to wait, 0 disables the limit, defaults to 5")
+            (@arg LOGIN_CLIENT_ATTEMPTS: --("login-client-attempts") +takes_value "failed logins per client address before it has to wait, 0 only limits users, defaults to 5")
+            (@arg LOGIN_DELAY: --("login-delay") +takes_value "seconds to wait after the first failed login beyond the free ones, doubled with every further one, defaults to 1")
+            (@arg LOGIN_MAX_DELAY: --("login-max-delay") +takes_value "longest wait in seconds after failed logins, defaults to 900")
+            (@arg CREDENTIAL_CACHE_TTL: --("credential-cache-ttl") +takes_value "seconds a verified password is remembered, 0 disables the cache, defaults to 60 or 5 with ldap")
+            (@arg CREDENTIAL_CACHE_SIZE: --("credential-cache-size") +takes_value "most verified passwords remembered at once, defaults to 1024")
+            (@arg HASHER: --hasher +takes_value possible_values(&["bcrypt", "argon2id"]) "algorithm for new password hashes, defaults to bcrypt")
+            (@arg BCRYPT_COST: --("bcrypt-cost") +takes_value "cost of new bcrypt hashes, defaults to 12")
+            (@arg ARGON2_MEMORY: --("argon2-memory") +takes_value "KiB of memory used by new argon2id hashes, defaults to 19456")
+            (@arg ARGON2_ITERATIONS: --("argon2-iterations") +takes_value "iterations of new argon2id hashes, defaults to 2")
+            (@arg SESSION_HOURS: --("session-hours") +takes_value "hours until a browser session ends, defaults to 24")
+            (@arg SECURE_COOKIES: --("secure-cookies") "only send session cookies over https")
+            (@arg OIDC_ISSUER: --("oidc-issuer") +takes_value "url of an openid connect provider browsers can log in with")
+            (@arg OIDC_CLIENT_ID: --("oidc-client-id") +takes_value "client id links is registered with at the openid connect provider")
+            (@arg OIDC_CLIENT_SECRET: --("oidc-client-secret") +takes_value "client secret links is registered with at the openid connect provider")
+            (@arg OIDC_REDIRECT_URI: --("oidc-redirect-uri") +takes_value "url of /_api/oidc/callback as registered at the openid connect provider")
+            (@arg OIDC_USERNAME_CLAIM: --("oidc-username-claim") +takes_value "id token claim used as username, defaults to email")
+            (@arg PROXY_AUTH_HEADER: --("proxy-auth-header") +takes_value "header an authenticating reverse proxy puts the username into, e.g. X-Forwarded-User")
+            (@arg TRUSTED_PROXIES: --("trusted-proxies") +takes_value "comma separated networks the proxy setting the username header runs in, e.g. 10.0.0.0/8")
+            (@arg LDAP_URL: --("ldap-url") +takes_value "check passwords with this ldap directory instead of the database, e.g. ldaps://ldap.example.com")
+            (@arg LDAP_USER_DN: --("ldap-user-dn") +takes_value "dn users bind to the directory as, {} is replaced by the username, e.g. uid={},ou=people,dc=example,dc=com")
         )
         (@subcommand add =>
             (about: "add entity to database")
             (@arg CONNECTION: -c --connection +takes_value "database connection string")
             (@subcommand user =>
                 (@arg NAME: +required)
+                (@arg ADMIN: --admin "allow the user to manage the redirects of everyone")
+                (@arg HASHER: --hasher +takes_value possible_values(&["bcrypt", "argon2id"]) "algorithm for new password hashes, defaults to bcrypt")
+                (@arg BCRYPT_COST: --("bcrypt-cost") +takes_value "cost of new bcrypt hashes, defaults to 12")
+                (@arg ARGON2_MEMORY: --("argon2-memory") +takes_value "KiB of memory used by new argon2id hashes, defaults to 19456")
+                (@arg ARGON2_ITERATIONS: --("argon2-iterations") +takes_value "iterations of new argon2id hashes, defaults to 2")
                 (@arg CONNECTION: -c --connection +takes_value "database connection string")
             )
         )
+        (@subcommand user =>
+            (about: "manage users")
+            (@arg CONNECTION: -c --connection +takes_value +global "database connection string")
+            (@subcommand list =>
+                (about: "list all users")
+            )
+            (@subcommand delete =>
+                (about: "delete a user")
+                (@arg NAME: +required)
+                (@arg CASCADE: --cascade conflicts_with[REASSIGN] "also delete the redirects of the user")
+                (@arg REASSIGN: --reassign +takes_value "give the redirects of the user to another one")
+            )
+            (@subcommand passwd =>
+                (about: "set a new password, read from stdin")
+                (@arg NAME: +required)
+                (@arg HASHER: --hasher +takes_value possible_values(&["bcrypt", "argon2id"]) "algorithm for new password hashes, defaults to bcrypt")
+                (@arg BCRYPT_COST: --("bcrypt-cost") +takes_value "cost of new bcrypt hashes, defaults to 12")
+                (@arg ARGON2_MEMORY: --("argon2-memory") +takes_value "KiB of memory used by new argon2id hashes, defaults to 19456")
+                (@arg ARGON2_ITERATIONS: --("argon2-iterations") +takes_value "iterations of new argon2id hashes, defaults to 2")
+            )
+            (@subcommand rename =>
+                (about: "rename a user")
+                (@arg NAME: +required)
+                (@arg NEW_NAME: +required)
+            )
+        )
+        (@subcommand group =>
+            (about: "manage groups sharing redirects")
+            (@arg CONNECTION: -c --connection +takes_value +global "database connection string")
+            (@subcommand list =>
+                (about: "list all groups with their members")
+            )
+            (@subcommand create =>
+                (about: "create a group")
+                (@arg NAME: +required)
+            )
+            (@subcommand delete =>
+                (about: "delete a group, its redirects go back to the users who created them")
+                (@arg NAME: +required)
+            )
+            (@subcommand add =>
+                (about: "add a user to a group")
+                (@arg NAME: +required)
+                (@arg USER: +required)
+            )
+            (@subcommand remove =>
+                (about: "remove a user from a group")
+                (@arg NAME: +required)
+                (@arg USER: +required)
+            )
+        )
+        (@subcommand namespace =>
+            (about: "reserve path prefixes for a user or group")
+            (@arg CONNECTION: -c --connection +takes_value +global "database connection string")
+            (@subcommand list =>
+                (about: "list all namespaces with their owners")
+            )
+            (@subcommand add =>
+                (about: "reserve a prefix, only its owner may create redirects below it")
+                (@arg PREFIX: +required)
+                (@arg USER: --user +takes_value conflicts_with[GROUP] required_unless[GROUP] "user owning the namespace")
+                (@arg GROUP: --group +takes_value "group owning the namespace")
+            )
+            (@subcommand remove =>
+                (about: "release a prefix")
+                (@arg PREFIX: +required)
+            )
+        )
+        (@subcommand token =>
+            (about: "manage api tokens of a user")
+            (@arg CONNECTION: -c --connection +takes_value +global "database connection string")
+            (@subcommand create =>
+                (about: "create a token, it is only shown once")
+                (@arg USER: +required)
+                (@arg NAME: +required)
+                (@arg EXPIRES: -e --expires +takes_value "days until the token expires, never by default")
+                (@arg SCOPE: -s --scope +takes_value +multiple "allowed scope: read, create, update, delete or admin, all by default")
+                (@arg PREFIX: --prefix +takes_value "only allow paths below this prefix")
+            )
+            (@subcommand list =>
+                (about: "list all tokens")
+                (@arg USER: +required)
+            )
+            (@subcommand revoke =>
+                (about: "delete a token")
+                (@arg USER: +required)
+                (@arg NAME: +required)
+            )
+        )
     )
     .get_matches();
 
     let result = match matches.subcommand() {
         ("run", matches) => run_server(matches),
         ("add", matches) => run_add(matches),
+        ("user", matches) => run_user(matches),
+        ("group", matches) => run_group(matches),
+        ("namespace", matches) => run_namespace(matches),
+        ("token", matches) => run_token(matches),
         _ => Err(ApplicationError::InvalidCommand),
     };
 
@@ -82,6 +226,108 @@ fn run_server(matches: Option<&ArgMatches>) -> Result<()> {
         config.blocking_threads = t;
     }
 
+    if let Some(i) = parse(matches, "STATS_INTERVAL") {
+        if i == 0 {
+            return Err(ApplicationError::Custom(
+                "stats interval must be at least 1",
+            ));
+        }
+        config.stats_interval = i;
+    }
+
+    if let Some(d) = parse(matches, "CLICK_RETENTION") {
+        config.click_retention = d;
+    }
+
+    if let Some(p) = parse(matches, "METRICS_PORT") {
+        config.metrics_port = Some(p);
+    }
+
+    config.public_metrics = matches.is_some_and(|m| m.is_present("PUBLIC_METRICS"))
+        || parse(matches, "PUBLIC_METRICS").unwrap_or(false);
+
+    if let Some(l) = parse(matches, "PASSWORD_MIN_LENGTH") {
+        config.password_policy.min_length = l;
+    }
+
+    if let Some(c) = parse(matches, "PASSWORD_MIN_CLASSES") {
+        config.password_policy.min_classes = c;
+    }
+
+    if let Some(a) = parse(matches, "LOGIN_ATTEMPTS") {
+        config.login_throttle.free_attempts = a;
+    }
+
+    if let Some(a) = parse(matches, "LOGIN_CLIENT_ATTEMPTS") {
+        config.login_throttle.client_attempts = a;
+    }
+
+    if let Some(d) = parse(matches, "LOGIN_DELAY") {
+        config.login_throttle.base_delay = d;
+    }
+
+    if let Some(d) = parse(matches, "LOGIN_MAX_DELAY") {
+        config.login_throttle.max_delay = d;
+    }
+
+    if let Some(t) = parse(matches, "CREDENTIAL_CACHE_TTL") {
+        config.credential_cache.ttl = t;
+    }
+
+    if let Some(s) = parse(matches, "CREDENTIAL_CACHE_SIZE") {
+        config.credential_cache.size = s;
+    }
+
+    if let Some(h) = parse(matches, "SESSION_HOURS") {
+        config.session.ttl_hours = h;
+    }
+
+    config.session.secure_cookie = matches.is_some_and(|m| m.is_present("SECURE_COOKIES"))
+        || parse(matches, "SECURE_COOKIES").unwrap_or(false);
+
+    if let Some(issuer) = parse(matches, "OIDC_ISSUER") {
+        config.session.oidc = Some(config::OidcConfig {
+            issuer,
+            client_id: parse(matches, "OIDC_CLIENT_ID")
+                .ok_or(ApplicationError::Custom("missing openid connect client id"))?,
+            client_secret: parse(matches, "OIDC_CLIENT_SECRET").ok_or(ApplicationError::Custom(
+                "missing openid connect client secret",
+            ))?,
+            redirect_uri: parse(matches, "OIDC_REDIRECT_URI").ok_or(ApplicationError::Custom(
+                "missing openid connect redirect uri",
+            ))?,
+            username_claim: parse(matches, "OIDC_USERNAME_CLAIM")
+                .unwrap_or_else(|| "email".to_string()),
+        });
+    }
+
+    if let Some(header) = parse(matches, "PROXY_AUTH_HEADER") {
+        let trusted = parse::<String>(matches, "TRUSTED_PROXIES")
+            .ok_or(ApplicationError::Custom("missing trusted proxies"))?
+            .split(',')
+            .map(|net| parse_net(net.trim()))
+            .collect::<Option<Vec<_>>>()
+            .ok_or(ApplicationError::Custom("invalid trusted proxy network"))?;
+
+        config.proxy_auth = Some(config::ProxyAuthConfig { header, trusted });
+    }
+
+    if let Some(url) = parse(matches, "LDAP_URL") {
+        let user_dn = parse::<String>(matches, "LDAP_USER_DN")
+            .filter(|dn| dn.contains("{}"))
+            .ok_or(ApplicationError::Custom(
+                "missing ldap user dn containing {} for the username",
+            ))?;
+
+        config.ldap = Some(config::LdapConfig { url, user_dn });
+
+        if parse::<u64>(matches, "CREDENTIAL_CACHE_TTL").is_none() {
+            config.credential_cache.ttl = config::CredentialCacheConfig::LDAP_TTL;
+        }
+    }
+
+    config.password_hash = hash_config(matches)?;
+
     command::run(&config)
 }
 
@@ -104,30 +350,238 @@ fn run_add_user(conn: Option<String>, matches: Option<&ArgMatches>) -> Result<()
         .ok_or(ApplicationError::NoConnectionString)?;
 
     let user = matches
-        .map(|matches| matches.value_of("NAME"))
-        .flatten()
+        .and_then(|matches| matches.value_of("NAME"))
         .map(|s| s.to_string())
         .ok_or(ApplicationError::InvalidCommand)?;
 
+    let password = read_password()?;
+
+    let config = config::AddConfig::User {
+        db_url: conn,
+        username: user,
+        password,
+        admin: matches.is_some_and(|matches| matches.is_present("ADMIN")),
+        hash: hash_config(matches)?,
+    };
+
+    command::add_user(&config)
+}
+
+/// How new password hashes are created, shared by the server and the commands setting
+/// passwords.
+fn hash_config(matches: Option<&ArgMatches>) -> Result<config::HashConfig> {
+    let mut config = config::HashConfig::default();
+
+    if let Some(a) = parse(matches, "HASHER") {
+        config.algorithm = a;
+    }
+
+    if let Some(c) = parse(matches, "BCRYPT_COST") {
+        config.bcrypt_cost = c;
+    }
+
+    if let Some(m) = parse(matches, "ARGON2_MEMORY") {
+        config.argon2_memory = m;
+    }
+
+    if let Some(i) = parse(matches, "ARGON2_ITERATIONS") {
+        config.argon2_iterations = i;
+    }
+
+    password::check_config(&config)?;
+
+    Ok(config)
+}
+
+fn read_password() -> Result<String> {
     println!("Enter password below:");
     let mut password = String::new();
     std::io::stdin()
         .read_line(&mut password)
         .map_err(|_| ApplicationError::Custom("failed to read password"))?;
 
-    password = password.trim().to_string();
+    let password = password.trim().to_string();
 
     if password.is_empty() {
         return Err(ApplicationError::Custom("password too short"));
     }
 
-    let config = config::AddConfig::User {
-        db_url: conn,
-        username: user,
-        password,
+    Ok(password)
+}
+
+fn run_user(matches: Option<&ArgMatches>) -> Result<()> {
+    let parent = matches;
+    let (command, matches) = matches
+        .map(|matches| matches.subcommand())
+        .ok_or(ApplicationError::InvalidCommand)?;
+    // the connection may be given before or after the subcommand
+    let db_url = parse(matches, "CONNECTION")
+        .or_else(|| parse(parent, "CONNECTION"))
+        .ok_or(ApplicationError::NoConnectionString)?;
+
+    let config = match command {
+        "list" => config::UserConfig::List { db_url },
+        "delete" => config::UserConfig::Delete {
+            db_url,
+            username: value(matches, "NAME")?,
+            redirects: if matches.is_some_and(|matches| matches.is_present("CASCADE")) {
+                config::OnDelete::Cascade
+            } else if let Ok(user) = value(matches, "REASSIGN") {
+                config::OnDelete::Reassign(user)
+            } else {
+                config::OnDelete::Refuse
+            },
+        },
+        "passwd" => config::UserConfig::Passwd {
+            db_url,
+            username: value(matches, "NAME")?,
+            password: read_password()?,
+            hash: hash_config(matches)?,
+        },
+        "rename" => config::UserConfig::Rename {
+            db_url,
+            username: value(matches, "NAME")?,
+            new_name: value(matches, "NEW_NAME")?,
+        },
+        _ => return Err(ApplicationError::InvalidCommand),
     };
 
-    command::add_user(&config)
+    command::user(&config)
+}
+
+fn run_group(matches: Option<&ArgMatches>) -> Result<()> {
+    let parent = matches;
+    let (command, matches) = matches
+        .map(|matches| matches.subcommand())
+        .ok_or(ApplicationError::InvalidCommand)?;
+    let db_url = parse(matches, "CONNECTION")
+        .or_else(|| parse(parent, "CONNECTION"))
+        .ok_or(ApplicationError::NoConnectionString)?;
+
+    let config = match command {
+        "list" => config::GroupConfig::List { db_url },
+        "create" => config::GroupConfig::Create {
+            db_url,
+            name: value(matches, "NAME")?,
+        },
+        "delete" => config::GroupConfig::Delete {
+            db_url,
+            name: value(matches, "NAME")?,
+        },
+        "add" => config::GroupConfig::Add {
+            db_url,
+            name: value(matches, "NAME")?,
+            username: value(matches, "USER")?,
+        },
+        "remove" => config::GroupConfig::Remove {
+            db_url,
+            name: value(matches, "NAME")?,
+            username: value(matches, "USER")?,
+        },
+        _ => return Err(ApplicationError::InvalidCommand),
+    };
+
+    command::group(&config)
+}
+
+fn run_namespace(matches: Option<&ArgMatches>) -> Result<()> {
+    let parent = matches;
+    let (command, matches) = matches
+        .map(|matches| matches.subcommand())
+        .ok_or(ApplicationError::InvalidCommand)?;
+    let db_url = parse(matches, "CONNECTION")
+        .or_else(|| parse(parent, "CONNECTION"))
+        .ok_or(ApplicationError::NoConnectionString)?;
+
+    let prefix = || {
+        let prefix = value(matches, "PREFIX")?.trim_matches('/').to_string();
+        if prefix.is_empty() {
+            Err(ApplicationError::Custom("prefix must not be empty"))
+        } else {
+            Ok(prefix)
+        }
+    };
+
+    let config = match command {
+        "list" => config::NamespaceConfig::List { db_url },
+        "add" => config::NamespaceConfig::Add {
+            db_url,
+            prefix: prefix()?,
+            owner: match value(matches, "USER") {
+                Ok(user) => config::NamespaceOwner::User(user),
+                Err(_) => config::NamespaceOwner::Group(value(matches, "GROUP")?),
+            },
+        },
+        "remove" => config::NamespaceConfig::Remove {
+            db_url,
+            prefix: prefix()?,
+        },
+        _ => return Err(ApplicationError::InvalidCommand),
+    };
+
+    command::namespace(&config)
+}
+
+fn run_token(matches: Option<&ArgMatches>) -> Result<()> {
+    let parent = matches;
+    let (command, matches) = matches
+        .map(|matches| matches.subcommand())
+        .ok_or(ApplicationError::InvalidCommand)?;
+    let db_url = parse(matches, "CONNECTION")
+        .or_else(|| parse(parent, "CONNECTION"))
+        .ok_or(ApplicationError::NoConnectionString)?;
+
+    let config = match command {
+        "create" => config::TokenConfig::Create {
+            db_url,
+            username: value(matches, "USER")?,
+            name: value(matches, "NAME")?,
+            expires_in_days: matches
+                .and_then(|matches| matches.value_of("EXPIRES"))
+                .map(|s| u32::from_str(s).map_err(|_| ApplicationError::Custom("invalid expiry")))
+                .transpose()?,
+            scopes: match matches.and_then(|matches| matches.values_of("SCOPE")) {
+                Some(names) => names
+                    .map(|name| {
+                        model::Scope::from_name(name)
+                            .ok_or(ApplicationError::Custom("unknown scope"))
+                    })
+                    .collect::<Result<_>>()?,
+                None => model::Scope::ALL.to_vec(),
+            },
+            prefix: matches
+                .and_then(|matches| matches.value_of("PREFIX"))
+                .map(|prefix| prefix.trim_matches('/').to_string())
+                .filter(|prefix| !prefix.is_empty()),
+        },
+        "list" => config::TokenConfig::List {
+            db_url,
+            username: value(matches, "USER")?,
+        },
+        "revoke" => config::TokenConfig::Revoke {
+            db_url,
+            username: value(matches, "USER")?,
+            name: value(matches, "NAME")?,
+        },
+        _ => return Err(ApplicationError::InvalidCommand),
+    };
+
+    command::token(&config)
+}
+
+/// The value of a required argument.
+fn value(matches: Option<&ArgMatches>, name: &str) -> Result<String> {
+    matches
+        .and_then(|matches| matches.value_of(name))
+        .map(|s| s.to_string())
+        .ok_or(ApplicationError::InvalidCommand)
+}
+
+/// A network like `10.0.0.0/8`, a single address is a network of its own.
+fn parse_net(net: &str) -> Option<IpNet> {
+    net.parse()
+        .ok()
+        .or_else(|| net.parse::<IpAddr>().ok().map(IpNet::from))
 }
 
 fn parse<T>(matches: Option<&ArgMatches>, name: &str) -> Option<T>
@@ -136,14 +590,11 @@ where
 {
     let project_name = String::from(build::PROJECT_NAME);
     matches
-        .map(|m| m.value_of(name))
-        .flatten()
-        .map(|s| T::from_str(s).ok())
-        .flatten()
+        .and_then(|m| m.value_of(name))
+        .and_then(|s| T::from_str(s).ok())
         .or_else(|| {
-            env::var(&format!("{}_{}", project_name.to_ascii_uppercase(), name))
+            env::var(format!("{}_{}", project_name.to_ascii_uppercase(), name))
                 .ok()
-                .map(|s| T::from_str(&s).ok())
-                .flatten()
+                .and_then(|s| T::from_str(&s).ok())
         })
 }
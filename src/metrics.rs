@@ -0,0 +1,91 @@
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram, register_int_counter_vec, register_int_gauge, Encoder, Histogram,
+    IntCounterVec, IntGauge, TextEncoder,
+};
+use sqlx::AnyPool;
+use std::convert::Infallible;
+use warp::{Filter, Rejection, Reply};
+
+lazy_static! {
+    static ref REQUESTS: IntCounterVec = register_int_counter_vec!(
+        "links_requests_total",
+        "Number of handled requests by outcome",
+        &["outcome"]
+    )
+    .unwrap();
+    pub static ref LOOKUP_SECONDS: Histogram = register_histogram!(
+        "links_redirect_lookup_seconds",
+        "Time spent looking up the target of a redirect"
+    )
+    .unwrap();
+    pub static ref PASSWORD_VERIFY_SECONDS: Histogram = register_histogram!(
+        "links_password_verify_seconds",
+        "Time spent verifying a password on the auth thread pool"
+    )
+    .unwrap();
+    pub static ref AUTH_QUEUE_DEPTH: IntGauge = register_int_gauge!(
+        "links_auth_queue_depth",
+        "Number of password verifications waiting for the auth thread pool"
+    )
+    .unwrap();
+    static ref DB_CONNECTIONS: IntGauge = register_int_gauge!(
+        "links_db_connections",
+        "Number of open database connections"
+    )
+    .unwrap();
+    static ref DB_IDLE_CONNECTIONS: IntGauge = register_int_gauge!(
+        "links_db_idle_connections",
+        "Number of idle database connections"
+    )
+    .unwrap();
+}
+
+/// How a request ended, counted by `links_requests_total`.
+#[derive(Debug, Clone, Copy)]
+pub enum Outcome {
+    Redirect,
+    NotFound,
+    Created,
+    Conflict,
+    Unauthorized,
+    Throttled,
+}
+
+impl Outcome {
+    fn label(self) -> &'static str {
+        match self {
+            Outcome::Redirect => "redirect",
+            Outcome::NotFound => "not_found",
+            Outcome::Created => "created",
+            Outcome::Conflict => "conflict",
+            Outcome::Unauthorized => "unauthorized",
+            Outcome::Throttled => "throttled",
+        }
+    }
+}
+
+pub fn count(outcome: Outcome) {
+    REQUESTS.with_label_values(&[outcome.label()]).inc();
+}
+
+/// Serves all metrics in the prometheus text format.
+pub fn filter(db_pool: AnyPool) -> impl Filter<Extract = (impl Reply,), Error = Rejection> + Clone {
+    warp::get().and_then(move || {
+        let db_pool = db_pool.clone();
+        async move { Ok::<_, Infallible>(gather(&db_pool)) }
+    })
+}
+
+fn gather(db_pool: &AnyPool) -> impl Reply {
+    DB_CONNECTIONS.set(db_pool.size() as i64);
+    DB_IDLE_CONNECTIONS.set(db_pool.num_idle() as i64);
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        log::warn!("failed to encode metrics: {}", e);
+    }
+
+    warp::reply::with_header(buffer, "Content-Type", encoder.format_type())
+}
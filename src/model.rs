@@ -1,39 +1,510 @@
+use serde::{Deserialize, Serialize};
+use sqlx::any::AnyKind;
+use warp::hyper::StatusCode;
+
+/// Format timestamps are stored in, matching `CURRENT_TIMESTAMP`.
+pub const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// The status code a redirect is answered with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum RedirectStatus {
+    /// 301 Moved Permanently
+    Permanent,
+    /// 302 Found
+    Found,
+    /// 307 Temporary Redirect
+    #[default]
+    Temporary,
+    /// 308 Permanent Redirect
+    PermanentPreserveMethod,
+}
+
+impl RedirectStatus {
+    pub fn from_code(code: i32) -> Option<Self> {
+        match code {
+            301 => Some(RedirectStatus::Permanent),
+            302 => Some(RedirectStatus::Found),
+            307 => Some(RedirectStatus::Temporary),
+            308 => Some(RedirectStatus::PermanentPreserveMethod),
+            _ => None,
+        }
+    }
+
+    pub fn status_code(self) -> StatusCode {
+        match self {
+            RedirectStatus::Permanent => StatusCode::MOVED_PERMANENTLY,
+            RedirectStatus::Found => StatusCode::FOUND,
+            RedirectStatus::Temporary => StatusCode::TEMPORARY_REDIRECT,
+            RedirectStatus::PermanentPreserveMethod => StatusCode::PERMANENT_REDIRECT,
+        }
+    }
+
+    pub fn code(self) -> i32 {
+        self.status_code().as_u16() as i32
+    }
+}
+
+/// What happens to the query string of a request when it is redirected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum QueryPolicy {
+    /// The query of the request is dropped.
+    #[default]
+    Drop,
+    /// The query of the request is appended to the one of the target.
+    Append,
+    /// Parameters of the request are added, unless the target already has them.
+    MergeWithTargetPrecedence,
+    /// Parameters of the request are added and replace those of the target.
+    MergeWithRequestPrecedence,
+}
+
+impl QueryPolicy {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "drop" => Some(QueryPolicy::Drop),
+            "append" => Some(QueryPolicy::Append),
+            "merge-with-target-precedence" => Some(QueryPolicy::MergeWithTargetPrecedence),
+            "merge-with-request-precedence" => Some(QueryPolicy::MergeWithRequestPrecedence),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            QueryPolicy::Drop => "drop",
+            QueryPolicy::Append => "append",
+            QueryPolicy::MergeWithTargetPrecedence => "merge-with-target-precedence",
+            QueryPolicy::MergeWithRequestPrecedence => "merge-with-request-precedence",
+        }
+    }
+
+    /// Combines the raw `query` of a request with the target `url` of its redirect.
+    pub fn apply(self, url: &str, query: &str) -> String {
+        if self == QueryPolicy::Drop || query.is_empty() {
+            return url.to_string();
+        }
+
+        let (url, fragment) = match url.find('#') {
+            Some(i) => url.split_at(i),
+            None => (url, ""),
+        };
+        let (base, target) = match url.split_once('?') {
+            Some((base, target)) => (base, target),
+            None => (url, ""),
+        };
+
+        fn key(pair: &str) -> &str {
+            pair.split('=').next().unwrap_or_default()
+        }
+
+        let target_pairs = target.split('&').filter(|s| !s.is_empty());
+        let request_pairs = query.split('&').filter(|s| !s.is_empty());
+
+        let pairs: Vec<&str> = match self {
+            QueryPolicy::MergeWithTargetPrecedence => {
+                let keys = target_pairs.clone().map(key).collect::<Vec<_>>();
+                target_pairs
+                    .chain(request_pairs.filter(|p| !keys.contains(&key(p))))
+                    .collect()
+            }
+            QueryPolicy::MergeWithRequestPrecedence => {
+                let keys = request_pairs.clone().map(key).collect::<Vec<_>>();
+                target_pairs
+                    .filter(|p| !keys.contains(&key(p)))
+                    .chain(request_pairs)
+                    .collect()
+            }
+            _ => target_pairs.chain(request_pairs).collect(),
+        };
+
+        format!("{}?{}{}", base, pairs.join("&"), fragment)
+    }
+}
+
+/// Size of the time buckets clicks are counted in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Bucket {
+    Hour,
+    #[default]
+    Day,
+    Week,
+}
+
+impl Bucket {
+    /// SQL expression for the start of the bucket `column` falls into, formatted like
+    /// [`TIMESTAMP_FORMAT`]. Weeks start on monday.
+    pub fn start_sql(self, kind: AnyKind, column: &str) -> String {
+        match (kind, self) {
+            (AnyKind::Postgres, bucket) => format!(
+                "TO_CHAR(DATE_TRUNC('{}', {}), 'YYYY-MM-DD HH24:MI:SS')",
+                match bucket {
+                    Bucket::Hour => "hour",
+                    Bucket::Day => "day",
+                    Bucket::Week => "week",
+                },
+                column
+            ),
+            (AnyKind::MySql, Bucket::Hour) => {
+                format!("DATE_FORMAT({}, '%Y-%m-%d %H:00:00')", column)
+            }
+            (AnyKind::MySql, Bucket::Day) => {
+                format!("DATE_FORMAT({}, '%Y-%m-%d 00:00:00')", column)
+            }
+            (AnyKind::MySql, Bucket::Week) => format!(
+                "DATE_FORMAT(DATE_SUB({0}, INTERVAL WEEKDAY({0}) DAY), '%Y-%m-%d 00:00:00')",
+                column
+            ),
+            (AnyKind::Sqlite, Bucket::Hour) => {
+                format!("STRFTIME('%Y-%m-%d %H:00:00', {})", column)
+            }
+            (AnyKind::Sqlite, Bucket::Day) => {
+                format!("STRFTIME('%Y-%m-%d 00:00:00', {})", column)
+            }
+            // to the next sunday unless it is one, then back to its monday
+            (AnyKind::Sqlite, Bucket::Week) => format!(
+                "STRFTIME('%Y-%m-%d 00:00:00', {}, 'weekday 0', '-6 days')",
+                column
+            ),
+        }
+    }
+}
+
+/// What an api token may be used for, passwords allow everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Scope {
+    /// List redirects and their analytics.
+    Read,
+    Create,
+    Update,
+    Delete,
+    /// Manage the account, i.e. api tokens and the password, and for admins the
+    /// redirects of everyone.
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 5] = [
+        Scope::Read,
+        Scope::Create,
+        Scope::Update,
+        Scope::Delete,
+        Scope::Admin,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "read" => Some(Scope::Read),
+            "create" => Some(Scope::Create),
+            "update" => Some(Scope::Update),
+            "delete" => Some(Scope::Delete),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Scope::Read => "read",
+            Scope::Create => "create",
+            Scope::Update => "update",
+            Scope::Delete => "delete",
+            Scope::Admin => "admin",
+        }
+    }
+
+    /// Parses scopes stored as a space separated list, unknown names are ignored.
+    pub fn parse_list(list: &str) -> Vec<Scope> {
+        list.split_whitespace()
+            .filter_map(Scope::from_name)
+            .collect()
+    }
+
+    pub fn join(scopes: &[Scope]) -> String {
+        scopes
+            .iter()
+            .map(|scope| scope.name())
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
 pub mod db {
     use sqlx::FromRow;
 
     #[derive(Debug, FromRow)]
     pub struct Entry {
         pub path: String,
+        pub user: String,
+        pub group: Option<String>,
         pub url: String,
+        pub status: i32,
+        pub query_policy: String,
         pub created: String,
+        pub updated: Option<String>,
+        pub hits: i64,
+        pub last_accessed: Option<String>,
+    }
+
+    #[derive(Debug, FromRow)]
+    pub struct Token {
+        pub name: String,
+        pub scopes: String,
+        pub prefix: Option<String>,
+        pub created: String,
+        pub expires: Option<String>,
+        pub last_used: Option<String>,
+    }
+
+    /// What a valid token grants its user.
+    #[derive(Debug, FromRow)]
+    pub struct Grant {
+        pub user: String,
+        pub is_admin: bool,
+        pub scopes: String,
+        pub prefix: Option<String>,
+    }
+
+    /// A path prefix only its owner may create redirects below.
+    #[derive(Debug, FromRow)]
+    pub struct Namespace {
+        pub prefix: String,
+        pub user: Option<String>,
+        pub group: Option<String>,
+    }
+
+    #[derive(Debug, FromRow)]
+    pub struct User {
+        pub username: String,
+        pub pw_hash: String,
+        pub is_admin: bool,
+    }
+
+    /// The user a session cookie belongs to.
+    #[derive(Debug, FromRow)]
+    pub struct Session {
+        pub user: String,
+        pub is_admin: bool,
+        pub csrf: String,
     }
 }
 
 pub mod http {
+    use super::{Bucket, QueryPolicy, RedirectStatus, Scope, TIMESTAMP_FORMAT};
     use chrono::{DateTime, NaiveDate, NaiveDateTime, Utc};
     use serde::{Deserialize, Serialize};
     #[derive(Debug, Deserialize)]
     pub struct NewEntryRequest {
         pub path: String,
         pub url: String,
+        /// Shares the redirect with the members of a group.
+        pub group: Option<String>,
+        #[serde(default)]
+        pub status: RedirectStatus,
+        #[serde(default)]
+        pub query: QueryPolicy,
+    }
+    /// Replaces all properties of an entry, used for `PUT`.
+    #[derive(Debug, Deserialize)]
+    pub struct UpdateEntryRequest {
+        pub url: String,
+        #[serde(default)]
+        pub status: RedirectStatus,
+        #[serde(default)]
+        pub query: QueryPolicy,
+        pub group: Option<String>,
+    }
+    /// Only changes the given properties of an entry, used for `PATCH`.
+    #[derive(Debug, Deserialize)]
+    pub struct PatchEntryRequest {
+        pub url: Option<String>,
+        pub status: Option<RedirectStatus>,
+        pub query: Option<QueryPolicy>,
+        /// `null` removes the group, leaving it out keeps it.
+        #[serde(default, deserialize_with = "present")]
+        pub group: Option<Option<String>>,
+    }
+
+    impl From<UpdateEntryRequest> for PatchEntryRequest {
+        fn from(request: UpdateEntryRequest) -> Self {
+            PatchEntryRequest {
+                url: Some(request.url),
+                status: Some(request.status),
+                query: Some(request.query),
+                group: Some(request.group),
+            }
+        }
     }
     #[derive(Debug, Serialize)]
     pub struct EntryResponse {
         path: String,
+        owner: String,
+        group: Option<String>,
         url: String,
+        status: RedirectStatus,
+        query: QueryPolicy,
         created: DateTime<Utc>,
+        updated: Option<DateTime<Utc>>,
+        hits: i64,
+        last_accessed: Option<DateTime<Utc>>,
     }
 
     impl From<super::db::Entry> for EntryResponse {
         fn from(entry: super::db::Entry) -> Self {
-            let date = NaiveDateTime::parse_from_str(&entry.created, "%Y-%m-%d %H:%M:%S")
-                .unwrap_or_else(|_| NaiveDate::from_ymd(0, 1, 1).and_hms(0, 0, 0));
-
             EntryResponse {
-                created: DateTime::from_utc(date, Utc),
+                created: parse_timestamp(&entry.created),
+                updated: entry.updated.as_deref().map(parse_timestamp),
+                hits: entry.hits,
+                last_accessed: entry.last_accessed.as_deref().map(parse_timestamp),
                 path: entry.path,
+                owner: entry.user,
+                group: entry.group,
                 url: entry.url,
+                status: RedirectStatus::from_code(entry.status).unwrap_or_default(),
+                query: QueryPolicy::from_name(&entry.query_policy).unwrap_or_default(),
+            }
+        }
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct AnalyticsRequest {
+        #[serde(default)]
+        pub bucket: Bucket,
+        pub path: Option<String>,
+        pub from: Option<DateTime<Utc>>,
+        pub to: Option<DateTime<Utc>>,
+    }
+    #[derive(Debug, Serialize)]
+    pub struct AnalyticsResponse {
+        pub bucket: Bucket,
+        pub from: DateTime<Utc>,
+        pub to: DateTime<Utc>,
+        pub clicks: Vec<BucketClicks>,
+        pub referrers: Vec<ReferrerClicks>,
+    }
+    #[derive(Debug, Serialize)]
+    pub struct BucketClicks {
+        pub start: DateTime<Utc>,
+        pub clicks: i64,
+    }
+    #[derive(Debug, Serialize)]
+    pub struct ReferrerClicks {
+        pub referrer: String,
+        pub clicks: i64,
+    }
+
+    #[derive(Debug, Deserialize)]
+    pub struct ReassignRequest {
+        pub owner: String,
+    }
+    #[derive(Debug, Deserialize)]
+    pub struct ChangePasswordRequest {
+        pub current: String,
+        pub new: String,
+    }
+    #[derive(Debug, Deserialize)]
+    pub struct LoginRequest {
+        pub username: String,
+        pub password: String,
+    }
+    /// Where an OpenID Connect provider sends the browser back to, with `error` instead
+    /// of `code` if the login failed there.
+    #[derive(Debug, Deserialize)]
+    pub struct OidcCallbackRequest {
+        pub code: Option<String>,
+        pub state: Option<String>,
+        pub error: Option<String>,
+    }
+    /// Has to be sent as `X-CSRF-Token` with every request changing something.
+    #[derive(Debug, Serialize)]
+    pub struct SessionResponse {
+        pub username: String,
+        pub csrf_token: String,
+    }
+    #[derive(Debug, Deserialize)]
+    pub struct NewTokenRequest {
+        pub name: String,
+        pub expires_in_days: Option<u32>,
+        /// Defaults to all scopes of the token used to create it.
+        pub scopes: Option<Vec<Scope>>,
+        /// Only allow paths below this prefix.
+        pub prefix: Option<String>,
+    }
+    #[derive(Debug, Serialize)]
+    pub struct TokenResponse {
+        name: String,
+        scopes: Vec<Scope>,
+        prefix: Option<String>,
+        created: DateTime<Utc>,
+        expires: Option<DateTime<Utc>>,
+        last_used: Option<DateTime<Utc>>,
+    }
+
+    impl From<super::db::Token> for TokenResponse {
+        fn from(token: super::db::Token) -> Self {
+            TokenResponse {
+                created: parse_timestamp(&token.created),
+                expires: token.expires.as_deref().map(parse_timestamp),
+                last_used: token.last_used.as_deref().map(parse_timestamp),
+                scopes: Scope::parse_list(&token.scopes),
+                prefix: token.prefix,
+                name: token.name,
             }
         }
     }
+    /// The only response containing the token itself, it can't be retrieved later.
+    #[derive(Debug, Serialize)]
+    pub struct NewTokenResponse {
+        pub token: String,
+        #[serde(flatten)]
+        pub info: TokenResponse,
+    }
+
+    /// Tells a field that is `null` apart from a missing one.
+    fn present<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+        T: Deserialize<'de>,
+    {
+        T::deserialize(deserializer).map(Some)
+    }
+
+    pub fn parse_timestamp(timestamp: &str) -> DateTime<Utc> {
+        let date = NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT)
+            .unwrap_or_else(|_| NaiveDate::from_ymd(0, 1, 1).and_hms(0, 0, 0));
+
+        DateTime::from_utc(date, Utc)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::QueryPolicy;
+
+    #[test]
+    fn query_policy() {
+        let url = "https://example.com/search?q=target&lang=de#top";
+        let query = "q=request&page=2";
+
+        assert_eq!(url, QueryPolicy::Drop.apply(url, query));
+        assert_eq!(
+            "https://example.com/search?q=target&lang=de&q=request&page=2#top",
+            QueryPolicy::Append.apply(url, query)
+        );
+        assert_eq!(
+            "https://example.com/search?q=target&lang=de&page=2#top",
+            QueryPolicy::MergeWithTargetPrecedence.apply(url, query)
+        );
+        assert_eq!(
+            "https://example.com/search?lang=de&q=request&page=2#top",
+            QueryPolicy::MergeWithRequestPrecedence.apply(url, query)
+        );
+        assert_eq!(
+            "https://example.com/?q=request",
+            QueryPolicy::Append.apply("https://example.com/", "q=request")
+        );
+    }
 }
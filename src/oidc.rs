@@ -0,0 +1,390 @@
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::OnceCell;
+
+use crate::config::OidcConfig;
+use crate::error::{ApiError, ApiResult};
+use crate::token;
+
+/// Name of the cookie keeping state and nonce of a login in progress.
+pub const COOKIE: &str = "links_oidc";
+/// Seconds a login at the provider may take.
+pub const LOGIN_SECS: u64 = 10 * 60;
+/// Seconds to wait for the provider to answer.
+const TIMEOUT_SECS: u64 = 10;
+/// How long the keys of the provider are used before they are fetched again.
+const JWKS_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+/// Tokens signed with an unknown key make the keys be fetched again at most this often,
+/// the provider may have rotated them.
+const JWKS_MIN_AGE: Duration = Duration::from_secs(60);
+
+/// Logs users in with an OpenID Connect provider using the authorization code flow.
+pub struct Oidc {
+    config: OidcConfig,
+    client: reqwest::Client,
+    /// Discovered on the first login, so links starts while the provider is down.
+    provider: OnceCell<Provider>,
+    jwks: Mutex<Option<(Instant, Arc<Jwks>)>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Provider {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+    #[serde(default)]
+    id_token_signing_alg_values_supported: Vec<String>,
+}
+
+impl Provider {
+    /// The algorithms id tokens may be signed with, RS256 unless the provider names
+    /// others. Never what the token itself claims.
+    fn algorithms(&self) -> Vec<Algorithm> {
+        let algorithms = self
+            .id_token_signing_alg_values_supported
+            .iter()
+            .filter_map(|alg| Algorithm::from_str(alg).ok())
+            .filter(|alg| HMAC.contains(alg) || RSA.contains(alg))
+            .collect::<Vec<_>>();
+
+        if algorithms.is_empty() {
+            vec![Algorithm::RS256]
+        } else {
+            algorithms
+        }
+    }
+}
+
+const HMAC: [Algorithm; 3] = [Algorithm::HS256, Algorithm::HS384, Algorithm::HS512];
+const RSA: [Algorithm; 3] = [Algorithm::RS256, Algorithm::RS384, Algorithm::RS512];
+
+#[derive(Debug, Deserialize)]
+struct TokenResponse {
+    id_token: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwks {
+    keys: Vec<Jwk>,
+}
+
+impl Jwks {
+    /// The key named `kid`.
+    fn find(&self, kid: &str) -> Option<&Jwk> {
+        self.keys.iter().find(|key| key.kid.as_deref() == Some(kid))
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Jwk {
+    kid: Option<String>,
+    n: Option<String>,
+    e: Option<String>,
+}
+
+/// Someone the provider vouches for.
+#[derive(Debug)]
+pub struct Login {
+    pub issuer: String,
+    /// Identifies the account at the provider, unlike the username it never changes.
+    pub subject: String,
+    pub username: String,
+}
+
+/// A login in progress, kept in a cookie until the provider sends the browser back.
+#[derive(Debug)]
+pub struct Pending {
+    pub state: String,
+    pub nonce: String,
+}
+
+impl Pending {
+    fn new() -> Self {
+        Pending {
+            state: token::random(),
+            nonce: token::random(),
+        }
+    }
+
+    pub fn to_cookie(&self) -> String {
+        format!("{}.{}", self.state, self.nonce)
+    }
+
+    pub fn from_cookie(cookie: &str) -> Option<Self> {
+        let mut parts = cookie.splitn(2, '.');
+
+        match (parts.next(), parts.next()) {
+            (Some(state), Some(nonce)) => Some(Pending {
+                state: state.to_string(),
+                nonce: nonce.to_string(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn provider_error(e: reqwest::Error) -> ApiError {
+    ApiError::Oidc(e.to_string())
+}
+
+impl Oidc {
+    pub fn new(config: OidcConfig) -> Self {
+        Oidc {
+            config,
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(TIMEOUT_SECS))
+                .build()
+                .expect("failed to create http client"),
+            provider: OnceCell::new(),
+            jwks: Mutex::new(None),
+        }
+    }
+
+    async fn provider(&self) -> ApiResult<&Provider> {
+        self.provider
+            .get_or_try_init(|| async {
+                let url = format!(
+                    "{}/.well-known/openid-configuration",
+                    self.config.issuer.trim_end_matches('/')
+                );
+                let provider = self
+                    .client
+                    .get(url)
+                    .send()
+                    .await
+                    .and_then(|res| res.error_for_status())
+                    .map_err(provider_error)?
+                    .json::<Provider>()
+                    .await
+                    .map_err(provider_error)?;
+
+                if provider.issuer.trim_end_matches('/') != self.config.issuer.trim_end_matches('/')
+                {
+                    return Err(ApiError::Oidc(format!(
+                        "provider claims to be {}",
+                        provider.issuer
+                    )));
+                }
+
+                Ok(provider)
+            })
+            .await
+    }
+
+    /// Starts a login, returns where to send the browser and what to remember until
+    /// it comes back.
+    pub async fn start(&self) -> ApiResult<(String, Pending)> {
+        let provider = self.provider().await?;
+        let pending = Pending::new();
+
+        let url = reqwest::Url::parse_with_params(
+            &provider.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.config.client_id),
+                ("redirect_uri", &self.config.redirect_uri),
+                ("scope", "openid email profile"),
+                ("state", &pending.state),
+                ("nonce", &pending.nonce),
+            ],
+        )
+        .map_err(|e| ApiError::Oidc(e.to_string()))?;
+
+        Ok((url.to_string(), pending))
+    }
+
+    /// Finishes a login by redeeming `code` for an id token, returns who the provider
+    /// vouches for.
+    pub async fn finish(&self, code: &str, state: &str, pending: &Pending) -> ApiResult<Login> {
+        if state != pending.state {
+            return Err(ApiError::Unauthorized);
+        }
+
+        let provider = self.provider().await?;
+
+        let token = self
+            .client
+            .post(&provider.token_endpoint)
+            .form(&[
+                ("grant_type", "authorization_code"),
+                ("code", code),
+                ("redirect_uri", &self.config.redirect_uri),
+                ("client_id", &self.config.client_id),
+                ("client_secret", &self.config.client_secret),
+            ])
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(provider_error)?
+            .json::<TokenResponse>()
+            .await
+            .map_err(provider_error)?;
+
+        let claims = self.verify(provider, &token.id_token).await?;
+
+        if claims.get("nonce").and_then(Value::as_str) != Some(pending.nonce.as_str()) {
+            return Err(ApiError::Unauthorized);
+        }
+
+        // an address the provider didn't verify could belong to someone else
+        if self.config.username_claim == "email"
+            && claims.get("email_verified").and_then(Value::as_bool) != Some(true)
+        {
+            return Err(ApiError::Unauthorized);
+        }
+
+        let claim = |name: &str| {
+            claims
+                .get(name)
+                .and_then(Value::as_str)
+                .filter(|value| !value.is_empty())
+                .map(|value| value.to_string())
+                .ok_or(ApiError::Unauthorized)
+        };
+
+        Ok(Login {
+            issuer: provider.issuer.clone(),
+            subject: claim("sub")?,
+            username: claim(&self.config.username_claim)?,
+        })
+    }
+
+    /// Checks signature, issuer, audience and expiry of an id token.
+    async fn verify(
+        &self,
+        provider: &Provider,
+        id_token: &str,
+    ) -> ApiResult<HashMap<String, Value>> {
+        let header = decode_header(id_token).map_err(|_| ApiError::Unauthorized)?;
+
+        let mut validation = Validation {
+            algorithms: provider.algorithms(),
+            ..Validation::default()
+        };
+        validation.iss = Some(provider.issuer.clone());
+        validation.set_audience(&[&self.config.client_id]);
+
+        if !validation.algorithms.contains(&header.alg) {
+            return Err(ApiError::Unauthorized);
+        }
+
+        let key = if HMAC.contains(&header.alg) {
+            DecodingKey::from_secret(self.config.client_secret.as_bytes()).into_static()
+        } else {
+            // a token has to name its key, even if the provider only has one
+            let kid = header.kid.as_deref().ok_or(ApiError::Unauthorized)?;
+            let jwks = self.jwks(provider, kid).await?;
+            let (n, e) = jwks
+                .find(kid)
+                .and_then(|key| key.n.as_deref().zip(key.e.as_deref()))
+                .ok_or(ApiError::Unauthorized)?;
+
+            DecodingKey::from_rsa_components(n, e).into_static()
+        };
+
+        decode(id_token, &key, &validation)
+            .map(|data| data.claims)
+            .map_err(|_| ApiError::Unauthorized)
+    }
+
+    /// The keys of the provider, fetched again once they are old or don't contain `kid`.
+    async fn jwks(&self, provider: &Provider, kid: &str) -> ApiResult<Arc<Jwks>> {
+        let cached = self.jwks.lock().unwrap().clone();
+
+        if let Some((fetched, jwks)) = cached {
+            let age = fetched.elapsed();
+            if age < JWKS_MAX_AGE && (age < JWKS_MIN_AGE || jwks.find(kid).is_some()) {
+                return Ok(jwks);
+            }
+        }
+
+        let jwks = self
+            .client
+            .get(&provider.jwks_uri)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(provider_error)?
+            .json::<Jwks>()
+            .await
+            .map_err(provider_error)?;
+        let jwks = Arc::new(jwks);
+
+        *self.jwks.lock().unwrap() = Some((Instant::now(), jwks.clone()));
+
+        Ok(jwks)
+    }
+}
+
+/// The `Set-Cookie` header value remembering a login in progress, it has to be sent
+/// along when the provider redirects back, so it can't be `SameSite=Strict`.
+pub fn cookie(value: &str, max_age_secs: u64, secure: bool) -> String {
+    format!(
+        "{}={}; Path=/{}/oidc; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        COOKIE,
+        value,
+        crate::server::API_PREFIX,
+        max_age_secs,
+        if secure { "; Secure" } else { "" }
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Jwk, Jwks, Provider};
+    use jsonwebtoken::Algorithm;
+
+    fn provider(algorithms: &[&str]) -> Provider {
+        Provider {
+            issuer: "https://id.example.com".to_string(),
+            authorization_endpoint: "https://id.example.com/authorize".to_string(),
+            token_endpoint: "https://id.example.com/token".to_string(),
+            jwks_uri: "https://id.example.com/jwks".to_string(),
+            id_token_signing_alg_values_supported: algorithms
+                .iter()
+                .map(|alg| alg.to_string())
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn algorithms() {
+        assert_eq!(vec![Algorithm::RS256], provider(&[]).algorithms());
+        assert_eq!(vec![Algorithm::RS256], provider(&["none"]).algorithms());
+        assert_eq!(
+            vec![Algorithm::RS512, Algorithm::HS256],
+            provider(&["RS512", "ES256", "HS256"]).algorithms()
+        );
+    }
+
+    #[test]
+    fn find_key() {
+        let key = |kid: Option<&str>| Jwk {
+            kid: kid.map(|kid| kid.to_string()),
+            n: None,
+            e: None,
+        };
+        let one = Jwks {
+            keys: vec![key(Some("a"))],
+        };
+        let two = Jwks {
+            keys: vec![key(Some("a")), key(Some("b"))],
+        };
+        let unnamed = Jwks {
+            keys: vec![key(None)],
+        };
+
+        assert!(one.find("a").is_some());
+        assert!(one.find("b").is_none());
+        assert_eq!(Some("b"), two.find("b").unwrap().kid.as_deref());
+        assert!(unnamed.find("").is_none());
+    }
+}
@@ -0,0 +1,312 @@
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2, Params,
+};
+use rand::rngs::OsRng;
+use std::{
+    convert::TryFrom,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, OnceLock,
+    },
+};
+use tokio::sync::oneshot;
+
+use crate::config::{HashAlgorithm, HashConfig, PasswordPolicy};
+use crate::error::{ApiError, ApiResult, ApplicationError, Result};
+use crate::metrics;
+
+/// bcrypt ignores everything after the first 72 bytes of a password.
+const MAX_LENGTH: usize = 72;
+const BCRYPT_PREFIXES: [&str; 3] = ["$2a$", "$2b$", "$2y$"];
+const ARGON2ID_PREFIX: &str = "$argon2id$";
+/// Costs the bcrypt crate accepts.
+const BCRYPT_COSTS: std::ops::RangeInclusive<u32> = 4..=31;
+
+/// Hashes and verifies passwords on its own thread pool, so the expensive hashing
+/// doesn't block the async workers. New hashes use the configured algorithm, existing
+/// ones are verified with whatever algorithm created them.
+pub struct Hasher {
+    thread_pool: rayon::ThreadPool,
+    config: HashConfig,
+    /// Hash with the same cost as new ones, checked for users who don't exist.
+    dummy: Arc<OnceLock<String>>,
+    /// How many passwords were checked, against real and dummy hashes alike.
+    checked: AtomicUsize,
+}
+
+impl Hasher {
+    pub fn new(thread_pool: rayon::ThreadPool, config: HashConfig) -> Self {
+        Hasher {
+            thread_pool,
+            config,
+            dummy: Arc::new(OnceLock::new()),
+            checked: AtomicUsize::new(0),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn checked(&self) -> usize {
+        self.checked.load(Ordering::SeqCst)
+    }
+
+    /// Checks `password` against `pw_hash`.
+    pub async fn verify(&self, password: String, pw_hash: String) -> ApiResult<bool> {
+        self.check(password, move || pw_hash).await
+    }
+
+    /// Does the same work as [`Hasher::verify`] for a user who doesn't exist, so the
+    /// response time doesn't tell whether an account exists. Never succeeds.
+    pub async fn verify_dummy(&self, password: String) -> ApiResult<bool> {
+        let dummy = self.dummy.clone();
+        let config = self.config.clone();
+
+        self.check(password, move || dummy_hash(&dummy, &config).to_string())
+            .await
+            .map(|_| false)
+    }
+
+    /// Computes the dummy hash ahead of time, otherwise the first login of an unknown
+    /// user would take twice as long.
+    pub fn init_dummy(&self) {
+        dummy_hash(&self.dummy, &self.config);
+    }
+
+    async fn check<F>(&self, password: String, pw_hash: F) -> ApiResult<bool>
+    where
+        F: FnOnce() -> String + Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+
+        self.checked.fetch_add(1, Ordering::SeqCst);
+        metrics::AUTH_QUEUE_DEPTH.inc();
+        self.thread_pool.spawn(move || {
+            metrics::AUTH_QUEUE_DEPTH.dec();
+            let _timer = metrics::PASSWORD_VERIFY_SECONDS.start_timer();
+            check_password(password, pw_hash(), tx)
+        });
+
+        rx.await
+            .map_err(|_| ApiError::Custom("failed to recieve check pw result"))
+    }
+
+    /// Hashes a new password with the configured algorithm.
+    pub async fn hash(&self, password: String) -> ApiResult<String> {
+        let (tx, rx) = oneshot::channel();
+        let config = self.config.clone();
+
+        self.thread_pool.spawn(move || {
+            tx.send(hash(&config, &password)).ok();
+        });
+
+        rx.await
+            .map_err(|_| ApiError::Custom("failed to recieve hash pw result"))?
+    }
+
+    /// Whether `pw_hash` was created with another algorithm or cost than new hashes are.
+    pub fn needs_rehash(&self, pw_hash: &str) -> bool {
+        match self.config.algorithm {
+            HashAlgorithm::Bcrypt => bcrypt_cost(pw_hash) != Some(self.config.bcrypt_cost),
+            HashAlgorithm::Argon2id => {
+                let params = PasswordHash::new(pw_hash)
+                    .ok()
+                    .filter(|hash| pw_hash.starts_with(ARGON2ID_PREFIX) && hash.salt.is_some())
+                    .and_then(|hash| Params::try_from(&hash).ok());
+
+                match params {
+                    Some(params) => {
+                        params.m_cost() != self.config.argon2_memory
+                            || params.t_cost() != self.config.argon2_iterations
+                    }
+                    None => true,
+                }
+            }
+        }
+    }
+}
+
+/// Makes sure hashes can be created with `config`. Checked on startup, otherwise a bad
+/// cost only shows once passwords are set and leaves the dummy hash empty.
+pub fn check_config(config: &HashConfig) -> Result<()> {
+    match config.algorithm {
+        HashAlgorithm::Bcrypt if !BCRYPT_COSTS.contains(&config.bcrypt_cost) => Err(
+            ApplicationError::Custom("bcrypt cost has to be between 4 and 31"),
+        ),
+        HashAlgorithm::Bcrypt => Ok(()),
+        HashAlgorithm::Argon2id => {
+            Params::new(config.argon2_memory, config.argon2_iterations, 1, None)
+                .map(|_| ())
+                .map_err(|_| ApplicationError::Custom("invalid argon2 memory or iterations"))
+        }
+    }
+}
+
+/// Hashes `password` with the algorithm and cost of `config`.
+pub fn hash(config: &HashConfig, password: &str) -> ApiResult<String> {
+    match config.algorithm {
+        HashAlgorithm::Bcrypt => bcrypt::hash(password, config.bcrypt_cost)
+            .map_err(|_| ApiError::Custom("failed to hash password")),
+        HashAlgorithm::Argon2id => {
+            let params = Params::new(config.argon2_memory, config.argon2_iterations, 1, None)
+                .map_err(|_| ApiError::Custom("invalid argon2 parameters"))?;
+            let salt = SaltString::generate(&mut OsRng);
+
+            Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params)
+                .hash_password(password.as_bytes(), &salt)
+                .map(|hash| hash.to_string())
+                .map_err(|_| ApiError::Custom("failed to hash password"))
+        }
+    }
+}
+
+/// Checks `password` against a hash of any supported algorithm, telling them apart by
+/// the prefix of the hash.
+fn verify_hash(password: &str, pw_hash: &str) -> bool {
+    if BCRYPT_PREFIXES
+        .iter()
+        .any(|prefix| pw_hash.starts_with(prefix))
+    {
+        bcrypt::verify(password, pw_hash).unwrap_or(false)
+    } else if pw_hash.starts_with(ARGON2ID_PREFIX) {
+        PasswordHash::new(pw_hash)
+            .map(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            })
+            .unwrap_or(false)
+    } else {
+        false
+    }
+}
+
+fn dummy_hash<'a>(dummy: &'a OnceLock<String>, config: &HashConfig) -> &'a str {
+    dummy.get_or_init(|| hash(config, "links dummy password").unwrap_or_default())
+}
+
+fn bcrypt_cost(pw_hash: &str) -> Option<u32> {
+    BCRYPT_PREFIXES
+        .iter()
+        .find_map(|prefix| pw_hash.strip_prefix(prefix))
+        .and_then(|rest| rest.get(..2))
+        .and_then(|cost| cost.parse().ok())
+}
+
+fn check_password(password: String, pw_hash: String, tx: oneshot::Sender<bool>) {
+    if !tx.is_closed() {
+        tx.send(verify_hash(&password, &pw_hash)).ok();
+    }
+}
+
+/// Makes sure a new password is long enough and mixes enough kinds of characters.
+pub fn check_policy(policy: &PasswordPolicy, password: &str) -> ApiResult<()> {
+    if password.chars().count() < policy.min_length {
+        return Err(ApiError::WeakPassword("password is too short"));
+    }
+
+    if password.len() > MAX_LENGTH {
+        return Err(ApiError::WeakPassword("password is too long"));
+    }
+
+    let classes = [
+        password.chars().any(|c| c.is_lowercase()),
+        password.chars().any(|c| c.is_uppercase()),
+        password.chars().any(|c| c.is_numeric()),
+        password.chars().any(|c| !c.is_alphanumeric()),
+    ];
+
+    if classes.iter().filter(|class| **class).count() < policy.min_classes {
+        return Err(ApiError::WeakPassword(
+            "password needs more kinds of characters",
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_config, check_policy, hash, verify_hash, Hasher};
+    use crate::config::{HashAlgorithm, HashConfig, PasswordPolicy};
+
+    #[test]
+    fn policy() {
+        let policy = PasswordPolicy {
+            min_length: 8,
+            min_classes: 3,
+        };
+
+        assert!(check_policy(&policy, "Blub123!").is_ok());
+        assert!(check_policy(&policy, "blub123!").is_ok());
+        assert!(check_policy(&policy, "Bl123!").is_err());
+        assert!(check_policy(&policy, "blubblub123").is_err());
+        assert!(check_policy(&policy, &"aA1".repeat(25)).is_err());
+    }
+
+    #[test]
+    fn algorithms() {
+        let bcrypt = HashConfig {
+            algorithm: HashAlgorithm::Bcrypt,
+            bcrypt_cost: 4,
+            ..HashConfig::default()
+        };
+        let argon2id = HashConfig {
+            algorithm: HashAlgorithm::Argon2id,
+            argon2_memory: 1024,
+            argon2_iterations: 1,
+            ..HashConfig::default()
+        };
+        let pool = || rayon::ThreadPoolBuilder::new().build().unwrap();
+
+        assert!(check_config(&bcrypt).is_ok());
+        assert!(check_config(&argon2id).is_ok());
+        for cost in &[3, 32] {
+            let invalid = HashConfig {
+                bcrypt_cost: *cost,
+                ..bcrypt.clone()
+            };
+            assert!(check_config(&invalid).is_err(), "{}", cost);
+        }
+        let invalid = HashConfig {
+            argon2_memory: 1,
+            ..argon2id.clone()
+        };
+        assert!(check_config(&invalid).is_err());
+
+        let bcrypt_hash = hash(&bcrypt, "blub").unwrap();
+        let argon2id_hash = hash(&argon2id, "blub").unwrap();
+        assert!(argon2id_hash.starts_with("$argon2id$"));
+
+        for pw_hash in &[&bcrypt_hash, &argon2id_hash] {
+            assert!(verify_hash("blub", pw_hash));
+            assert!(!verify_hash("bulb", pw_hash));
+        }
+        assert!(!verify_hash("blub", "blub"));
+
+        let hasher = Hasher::new(pool(), bcrypt.clone());
+        assert!(!hasher.needs_rehash(&bcrypt_hash));
+        assert!(hasher.needs_rehash(&argon2id_hash));
+
+        let hasher = Hasher::new(
+            pool(),
+            HashConfig {
+                bcrypt_cost: 5,
+                ..bcrypt
+            },
+        );
+        assert!(hasher.needs_rehash(&bcrypt_hash));
+
+        let hasher = Hasher::new(pool(), argon2id.clone());
+        assert!(hasher.needs_rehash(&bcrypt_hash));
+        assert!(!hasher.needs_rehash(&argon2id_hash));
+
+        let hasher = Hasher::new(
+            pool(),
+            HashConfig {
+                argon2_iterations: 2,
+                ..argon2id
+            },
+        );
+        assert!(hasher.needs_rehash(&argon2id_hash));
+    }
+}
@@ -0,0 +1,360 @@
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use std::{cmp::Ordering, str::FromStr};
+
+use crate::error::ApiError;
+
+/// Name of the placeholder in a target url that is replaced by the part of the path a
+/// wildcard matched.
+const WILDCARD_PLACEHOLDER: &str = "*";
+
+/// Everything but unreserved characters gets encoded when a captured segment is put
+/// into a url, so it can't change the structure of the url.
+const SEGMENT: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'-')
+    .remove(b'.')
+    .remove(b'_')
+    .remove(b'~');
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Segment {
+    Literal(String),
+    Param(String),
+    Wildcard,
+}
+
+/// The path of a redirect. Either an exact path like `youtube` or a pattern made of
+/// literal segments, named placeholders like `{id}` and an optional trailing wildcard
+/// like `gh/*`, which matches `gh` and everything below it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Pattern {
+    segments: Vec<Segment>,
+}
+
+/// The result of matching a request path against a [`Pattern`].
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Match {
+    params: Vec<(String, String)>,
+    rest: String,
+}
+
+impl FromStr for Pattern {
+    type Err = ApiError;
+
+    fn from_str(path: &str) -> Result<Self, Self::Err> {
+        let invalid = || ApiError::InvalidPattern(path.to_string());
+        let mut segments = Vec::new();
+
+        for segment in split(path) {
+            if segments.last() == Some(&Segment::Wildcard) {
+                return Err(invalid());
+            }
+
+            let segment = if segment == "*" {
+                Segment::Wildcard
+            } else if let Some(name) = placeholder(segment) {
+                if !is_name(name) || segments.contains(&Segment::Param(name.to_string())) {
+                    return Err(invalid());
+                }
+                Segment::Param(name.to_string())
+            } else if segment.contains(&['*', '{', '}'][..]) {
+                return Err(invalid());
+            } else {
+                Segment::Literal(segment.to_string())
+            };
+
+            segments.push(segment);
+        }
+
+        Ok(Pattern { segments })
+    }
+}
+
+impl Pattern {
+    /// Whether the pattern only matches a single path.
+    pub fn is_exact(&self) -> bool {
+        self.segments
+            .iter()
+            .all(|segment| matches!(segment, Segment::Literal(_)))
+    }
+
+    /// Whether the pattern starts with a placeholder or the wildcard, so it also catches
+    /// paths nobody registered.
+    pub fn is_catch_all(&self) -> bool {
+        !self.is_exact() && self.prefix().is_empty()
+    }
+
+    /// The literal part of the pattern in front of the first placeholder, used to look
+    /// up candidates for a request path with the help of [`prefixes`].
+    pub fn prefix(&self) -> String {
+        self.segments
+            .iter()
+            .map_while(|segment| match segment {
+                Segment::Literal(s) => Some(s.as_str()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("/")
+    }
+
+    /// Matches the already decoded request `path`, the way patterns are written.
+    pub fn matches(&self, path: &str) -> Option<Match> {
+        let mut parts = split(path).peekable();
+        let mut m = Match::default();
+
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(s) => {
+                    if parts.next() != Some(s.as_str()) {
+                        return None;
+                    }
+                }
+                Segment::Param(name) => {
+                    m.params.push((name.clone(), parts.next()?.to_string()));
+                }
+                Segment::Wildcard => {
+                    m.rest = parts.collect::<Vec<_>>().join("/");
+                    return Some(m);
+                }
+            }
+        }
+
+        match parts.peek() {
+            None => Some(m),
+            Some(_) => None,
+        }
+    }
+
+    /// Checks that every placeholder used in `url` is provided by this pattern.
+    pub fn check_url(&self, url: &str) -> Result<(), ApiError> {
+        for name in placeholders(url) {
+            let known = if name == WILDCARD_PLACEHOLDER {
+                self.segments.contains(&Segment::Wildcard)
+            } else {
+                self.segments.contains(&Segment::Param(name.to_string()))
+            };
+
+            if !known {
+                return Err(ApiError::UnknownPlaceholder(name.to_string()));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Orders patterns from most to least specific. Segment by segment literals win
+    /// over named placeholders, which win over the wildcard.
+    pub fn precedence(&self, other: &Pattern) -> Ordering {
+        fn rank(pattern: &Pattern) -> impl Iterator<Item = u8> + '_ {
+            let end = if pattern.segments.last() == Some(&Segment::Wildcard) {
+                None
+            } else {
+                Some(1)
+            };
+
+            pattern
+                .segments
+                .iter()
+                .map(|segment| match segment {
+                    Segment::Literal(_) => 3,
+                    Segment::Param(_) => 2,
+                    Segment::Wildcard => 0,
+                })
+                .chain(end)
+        }
+
+        rank(other).cmp(rank(self))
+    }
+}
+
+impl Match {
+    /// Builds the target of a redirect from its url. Named placeholders are replaced
+    /// by the captured segments, the matched rest of the path is either substituted for
+    /// `{*}` or appended to the path of the url.
+    pub fn expand(&self, url: &str) -> String {
+        let mut target = url.to_string();
+
+        for (name, value) in &self.params {
+            let value = utf8_percent_encode(value, SEGMENT).to_string();
+            target = target.replace(&format!("{{{}}}", name), &value);
+        }
+
+        let wildcard = format!("{{{}}}", WILDCARD_PLACEHOLDER);
+        if target.contains(&wildcard) {
+            return target.replace(&wildcard, &self.rest);
+        }
+
+        if self.rest.is_empty() {
+            return target;
+        }
+
+        let end = target.find(['?', '#']).unwrap_or(target.len());
+        let (base, query) = target.split_at(end);
+
+        format!("{}/{}{}", base.trim_end_matches('/'), self.rest, query)
+    }
+}
+
+/// Replaces all placeholders of `url` with a plain value, so it can be validated.
+pub fn fill_placeholders(url: &str) -> String {
+    placeholders(url).fold(url.to_string(), |url, name| {
+        url.replace(&format!("{{{}}}", name), "x")
+    })
+}
+
+pub fn has_placeholders(url: &str) -> bool {
+    placeholders(url).next().is_some()
+}
+
+/// All prefixes of `path` a [`Pattern`] could have if it matches `path`, longest first.
+pub fn prefixes(path: &str) -> Vec<String> {
+    let segments = split(path).collect::<Vec<_>>();
+
+    (0..=segments.len())
+        .rev()
+        .map(|len| segments[..len].join("/"))
+        .collect()
+}
+
+/// Whether `path` is `prefix` itself or one of the paths below it.
+pub fn is_below(path: &str, prefix: &str) -> bool {
+    let path = path.trim_matches('/');
+    let prefix = prefix.trim_matches('/');
+
+    prefix.is_empty()
+        || path == prefix
+        || path
+            .strip_prefix(prefix)
+            .is_some_and(|rest| rest.starts_with('/'))
+}
+
+fn split(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|s| !s.is_empty())
+}
+
+fn placeholder(s: &str) -> Option<&str> {
+    s.strip_prefix('{')?.strip_suffix('}')
+}
+
+fn placeholders(url: &str) -> impl Iterator<Item = &str> {
+    url.split('{')
+        .skip(1)
+        .filter_map(|s| s.split_once('}').map(|(name, _)| name))
+        .filter(|name| *name == WILDCARD_PLACEHOLDER || is_name(name))
+}
+
+fn is_name(s: &str) -> bool {
+    !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_below, prefixes, Pattern};
+    use std::cmp::Ordering;
+
+    #[test]
+    fn parse() {
+        assert!("youtube".parse::<Pattern>().unwrap().is_exact());
+        assert!(!"gh/*".parse::<Pattern>().unwrap().is_exact());
+        assert!(!"t/{project}".parse::<Pattern>().unwrap().is_exact());
+        assert_eq!("gh", "gh/*".parse::<Pattern>().unwrap().prefix());
+        assert_eq!("t", "t/{project}/{id}".parse::<Pattern>().unwrap().prefix());
+        assert_eq!("", "*".parse::<Pattern>().unwrap().prefix());
+        assert!("*".parse::<Pattern>().unwrap().is_catch_all());
+        assert!("{team}/wiki".parse::<Pattern>().unwrap().is_catch_all());
+        assert!(!"gh/*".parse::<Pattern>().unwrap().is_catch_all());
+        assert!(!"youtube".parse::<Pattern>().unwrap().is_catch_all());
+        assert!("gh/*/blub".parse::<Pattern>().is_err());
+        assert!("gh/blub*".parse::<Pattern>().is_err());
+        assert!("t/{id}/{id}".parse::<Pattern>().is_err());
+        assert!("t/{}".parse::<Pattern>().is_err());
+        assert!("t/ABC-{id}".parse::<Pattern>().is_err());
+    }
+
+    #[test]
+    fn wildcard() {
+        let pattern = "gh/*".parse::<Pattern>().unwrap();
+
+        let m = pattern.matches("gh/tobiasmoldan/links").unwrap();
+        assert_eq!(
+            "https://github.com/tobiasmoldan/links",
+            m.expand("https://github.com/")
+        );
+        assert_eq!(
+            "https://github.com/tobiasmoldan/links?tab=readme",
+            m.expand("https://github.com?tab=readme")
+        );
+        assert_eq!(
+            "https://example.com/?repo=tobiasmoldan/links",
+            m.expand("https://example.com/?repo={*}")
+        );
+
+        let m = pattern.matches("gh").unwrap();
+        assert_eq!("https://github.com/", m.expand("https://github.com/"));
+
+        assert!(pattern.matches("gitlab/blub").is_none());
+    }
+
+    #[test]
+    fn template() {
+        let pattern = "t/{project}/{id}".parse::<Pattern>().unwrap();
+        let url = "https://tracker.example.com/browse/{project}-{id}";
+
+        assert!(pattern.check_url(url).is_ok());
+        assert!(pattern.check_url("https://example.com/{key}").is_err());
+        assert!(pattern.check_url("https://example.com/{*}").is_err());
+
+        let m = pattern.matches("t/ABC/123").unwrap();
+        assert_eq!("https://tracker.example.com/browse/ABC-123", m.expand(url));
+
+        let m = pattern.matches("t/a b/?&").unwrap();
+        assert_eq!(
+            "https://tracker.example.com/browse/a%20b-%3F%26",
+            m.expand(url)
+        );
+
+        assert!(pattern.matches("t/ABC").is_none());
+        assert!(pattern.matches("t/ABC/123/blub").is_none());
+    }
+
+    #[test]
+    fn exact() {
+        let pattern = "youtube".parse::<Pattern>().unwrap();
+
+        assert!(pattern.matches("youtube").is_some());
+        assert!(pattern.matches("youtube/blub").is_none());
+    }
+
+    #[test]
+    fn precedence() {
+        let parse = |s: &str| s.parse::<Pattern>().unwrap();
+
+        assert_eq!(Ordering::Less, parse("gh/*").precedence(&parse("*")));
+        assert_eq!(Ordering::Less, parse("t/{id}").precedence(&parse("t/*")));
+        assert_eq!(
+            Ordering::Less,
+            parse("t/abc/{id}").precedence(&parse("t/{project}/new"))
+        );
+        assert_eq!(
+            Ordering::Less,
+            parse("t/{id}").precedence(&parse("t/{id}/*"))
+        );
+    }
+
+    #[test]
+    fn path_prefixes() {
+        assert_eq!(vec!["a/b", "a", ""], prefixes("a/b"));
+        assert_eq!(vec![""], prefixes(""));
+    }
+
+    #[test]
+    fn path_below_prefix() {
+        assert!(is_below("deploy", "deploy"));
+        assert!(is_below("deploy/a/b", "deploy/"));
+        assert!(is_below("deploy/*", "deploy"));
+        assert!(!is_below("deployment", "deploy"));
+        assert!(!is_below("blub/deploy", "deploy"));
+        assert!(is_below("blub", ""));
+    }
+}
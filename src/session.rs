@@ -0,0 +1,94 @@
+use chrono::{Duration, Utc};
+use sqlx::AnyPool;
+
+use crate::model::{db, TIMESTAMP_FORMAT};
+use crate::token;
+
+/// Name of the cookie holding the session id.
+pub const COOKIE: &str = "links_session";
+/// Header requests authenticated by the cookie have to repeat the csrf token in.
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// A new session, the id goes into the cookie and only its hash is stored.
+#[derive(Debug)]
+pub struct NewSession {
+    pub id: String,
+    pub csrf: String,
+}
+
+fn now() -> String {
+    Utc::now().format(TIMESTAMP_FORMAT).to_string()
+}
+
+/// Starts a session for `username` that ends after `ttl_hours`, expired sessions are
+/// removed on the way.
+pub async fn create(db_pool: &AnyPool, username: &str, ttl_hours: u32) -> sqlx::Result<NewSession> {
+    let session = NewSession {
+        id: token::random(),
+        csrf: token::random(),
+    };
+    let expires = (Utc::now() + Duration::hours(ttl_hours.into()))
+        .format(TIMESTAMP_FORMAT)
+        .to_string();
+
+    sqlx::query("DELETE FROM session WHERE expires <= $1")
+        .bind(now())
+        .execute(db_pool)
+        .await?;
+
+    sqlx::query(
+        "INSERT INTO session (hash, \"user\", csrf, created, expires) VALUES ($1,$2,$3,$4,$5)",
+    )
+    .bind(token::hash(&session.id))
+    .bind(username)
+    .bind(&session.csrf)
+    .bind(now())
+    .bind(expires)
+    .execute(db_pool)
+    .await?;
+
+    Ok(session)
+}
+
+/// Looks up the user of an unexpired session.
+pub async fn verify(db_pool: &AnyPool, id: &str) -> sqlx::Result<Option<db::Session>> {
+    sqlx::query_as::<_, db::Session>(
+        "SELECT s.\"user\", u.is_admin, s.csrf FROM session s INNER JOIN \"user\" u ON u.username = s.\"user\" WHERE s.hash = $1 AND s.expires > $2",
+    )
+    .bind(token::hash(id))
+    .bind(now())
+    .fetch_optional(db_pool)
+    .await
+}
+
+/// Ends a session, returns whether it existed.
+pub async fn delete(db_pool: &AnyPool, id: &str) -> sqlx::Result<bool> {
+    let rows = sqlx::query("DELETE FROM session WHERE hash = $1")
+        .bind(token::hash(id))
+        .execute(db_pool)
+        .await?
+        .rows_affected();
+
+    Ok(rows == 1)
+}
+
+/// Ends every session of `username`, e.g. after their password changed.
+pub async fn delete_all(db_pool: &AnyPool, username: &str) -> sqlx::Result<()> {
+    sqlx::query("DELETE FROM session WHERE \"user\" = $1")
+        .bind(username)
+        .execute(db_pool)
+        .await?;
+
+    Ok(())
+}
+
+/// The `Set-Cookie` header value for a session, an empty `id` removes the cookie.
+pub fn cookie(id: &str, max_age_secs: u64, secure: bool) -> String {
+    format!(
+        "{}={}; Path=/; Max-Age={}; HttpOnly; SameSite=Strict{}",
+        COOKIE,
+        id,
+        max_age_secs,
+        if secure { "; Secure" } else { "" }
+    )
+}
@@ -0,0 +1,237 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::config::ThrottleConfig;
+use crate::error::{ApiError, ApiResult};
+
+/// Number of tracked users and clients at which forgotten ones are removed.
+const PRUNE_SIZE: usize = 10_000;
+
+/// Slows down password guessing. Every failed login is counted for the user and unless
+/// disabled for the client it came from, once either has used up its free attempts it
+/// has to wait before trying again, twice as long after every further failure.
+#[derive(Clone)]
+pub struct Throttle {
+    config: ThrottleConfig,
+    failures: Arc<Mutex<HashMap<Key, Failures>>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    User(String),
+    Addr(IpAddr),
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Throttle {
+            config,
+            failures: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.config.free_attempts > 0
+    }
+
+    /// Makes sure neither `username` nor `addr` has to wait, answers with `429` otherwise.
+    pub fn check(&self, username: &str, addr: Option<IpAddr>) -> ApiResult<()> {
+        if !self.enabled() {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let failures = self.failures.lock().unwrap();
+
+        let wait = self
+            .keys(username, addr)
+            .filter_map(|key| Some((failures.get(&key)?, key)))
+            .filter_map(|(failures, key)| self.locked_until(&key, failures))
+            .filter(|until| *until > now)
+            .max();
+
+        match wait {
+            // rounded up, retrying a moment too early would fail again
+            Some(until) => Err(ApiError::TooManyAttempts((until - now).as_secs() + 1)),
+            None => Ok(()),
+        }
+    }
+
+    /// Records a failed login of `username` from `addr`.
+    pub fn fail(&self, username: &str, addr: Option<IpAddr>) {
+        if !self.enabled() {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut failures = self.failures.lock().unwrap();
+
+        if failures.len() >= PRUNE_SIZE {
+            failures.retain(|key, failures| !self.forgotten(key, failures, now));
+        }
+
+        for key in self.keys(username, addr) {
+            let forgotten = failures
+                .get(&key)
+                .is_some_and(|failures| self.forgotten(&key, failures, now));
+            let failures = failures.entry(key).or_insert(Failures {
+                count: 0,
+                last: now,
+            });
+
+            if forgotten {
+                failures.count = 0;
+            }
+
+            failures.count = failures.count.saturating_add(1);
+            failures.last = now;
+        }
+    }
+
+    /// Forgets the failures of `username` after a successful login. Those of the client
+    /// are kept, otherwise one valid account would be enough to guess others.
+    pub fn succeed(&self, username: &str) {
+        if self.enabled() {
+            self.failures
+                .lock()
+                .unwrap()
+                .remove(&Key::User(username.to_string()));
+        }
+    }
+
+    fn keys(&self, username: &str, addr: Option<IpAddr>) -> impl Iterator<Item = Key> {
+        let addr = addr.filter(|_| self.config.client_attempts > 0);
+        std::iter::once(Key::User(username.to_string())).chain(addr.map(Key::Addr))
+    }
+
+    fn delay(&self, key: &Key, count: u32) -> Option<Duration> {
+        let free_attempts = match key {
+            Key::User(_) => self.config.free_attempts,
+            Key::Addr(_) => self.config.client_attempts,
+        };
+        let doublings = count.checked_sub(free_attempts)?;
+        let secs = self
+            .config
+            .base_delay
+            .saturating_mul(2u64.saturating_pow(doublings))
+            .min(self.config.max_delay);
+
+        Some(Duration::from_secs(secs))
+    }
+
+    fn locked_until(&self, key: &Key, failures: &Failures) -> Option<Instant> {
+        self.delay(key, failures.count)
+            .map(|delay| failures.last + delay)
+    }
+
+    /// Failures are forgotten once the longest delay has passed after the last one ended.
+    fn forgotten(&self, key: &Key, failures: &Failures, now: Instant) -> bool {
+        let until = self.locked_until(key, failures).unwrap_or(failures.last);
+        now >= until + Duration::from_secs(self.config.max_delay)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Throttle;
+    use crate::config::ThrottleConfig;
+    use crate::error::ApiError;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const ADDR: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)));
+    const OTHER_ADDR: Option<IpAddr> = Some(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 2)));
+
+    fn throttle() -> Throttle {
+        Throttle::new(ThrottleConfig {
+            free_attempts: 3,
+            client_attempts: 3,
+            base_delay: 10,
+            max_delay: 60,
+        })
+    }
+
+    fn wait(throttle: &Throttle, username: &str, addr: Option<IpAddr>) -> Option<u64> {
+        match throttle.check(username, addr) {
+            Ok(()) => None,
+            Err(ApiError::TooManyAttempts(secs)) => Some(secs),
+            Err(e) => panic!("unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn backoff() {
+        let throttle = throttle();
+
+        for _ in 0..2 {
+            throttle.fail("test", ADDR);
+            assert_eq!(None, wait(&throttle, "test", ADDR));
+        }
+
+        throttle.fail("test", ADDR);
+        assert_eq!(Some(10), wait(&throttle, "test", ADDR));
+        // the user is locked from everywhere, the client for every user
+        assert_eq!(Some(10), wait(&throttle, "test", OTHER_ADDR));
+        assert_eq!(Some(10), wait(&throttle, "other", ADDR));
+        assert_eq!(None, wait(&throttle, "other", OTHER_ADDR));
+
+        throttle.fail("test", ADDR);
+        assert_eq!(Some(20), wait(&throttle, "test", None));
+
+        for _ in 0..5 {
+            throttle.fail("test", ADDR);
+        }
+        assert_eq!(Some(60), wait(&throttle, "test", None));
+    }
+
+    #[test]
+    fn success_resets_user() {
+        let throttle = throttle();
+
+        for _ in 0..3 {
+            throttle.fail("test", ADDR);
+        }
+        throttle.succeed("test");
+
+        assert_eq!(None, wait(&throttle, "test", OTHER_ADDR));
+        assert!(wait(&throttle, "other", ADDR).is_some());
+    }
+
+    #[test]
+    fn clients_unlimited() {
+        let throttle = Throttle::new(ThrottleConfig {
+            client_attempts: 0,
+            ..ThrottleConfig::default()
+        });
+
+        for _ in 0..5 {
+            throttle.fail("test", ADDR);
+        }
+
+        assert!(wait(&throttle, "test", OTHER_ADDR).is_some());
+        assert_eq!(None, wait(&throttle, "other", ADDR));
+    }
+
+    #[test]
+    fn disabled() {
+        let throttle = Throttle::new(ThrottleConfig {
+            free_attempts: 0,
+            ..ThrottleConfig::default()
+        });
+
+        for _ in 0..100 {
+            throttle.fail("test", ADDR);
+        }
+
+        assert_eq!(None, wait(&throttle, "test", ADDR));
+    }
+}
@@ -0,0 +1,153 @@
+use chrono::{Duration, Utc};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use sqlx::{Any, AnyPool, Executor};
+
+use crate::model::{db, Scope, TIMESTAMP_FORMAT};
+
+/// Every token starts with this, so a leaked one is easy to recognize.
+const TOKEN_PREFIX: &str = "links_";
+/// Number of random bytes in a token.
+const TOKEN_BYTES: usize = 32;
+const SELECT_TOKEN: &str = "SELECT name, scopes, prefix, created, expires, last_used FROM token";
+
+/// Creates a new random token, only its hash is ever stored.
+pub fn generate() -> String {
+    format!("{}{}", TOKEN_PREFIX, random())
+}
+
+/// Random bytes encoded to be safe in urls, headers and cookies.
+pub fn random() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Tokens are long random strings, unlike passwords they don't need a slow hash.
+pub fn hash(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+fn now() -> String {
+    Utc::now().format(TIMESTAMP_FORMAT).to_string()
+}
+
+/// Stores a new token named `name` for `username`, which expires after
+/// `expires_in_days` if given and can only be used for paths below `prefix`. Returns
+/// the token or `None` if the user already has a token with that name.
+pub async fn create<'e, E>(
+    executor: E,
+    username: &str,
+    name: &str,
+    expires_in_days: Option<u32>,
+    scopes: &[Scope],
+    prefix: Option<&str>,
+) -> sqlx::Result<Option<String>>
+where
+    E: Executor<'e, Database = Any>,
+{
+    let token = generate();
+    let expires = expires_in_days.map(|days| {
+        (Utc::now() + Duration::days(days.into()))
+            .format(TIMESTAMP_FORMAT)
+            .to_string()
+    });
+
+    let rows = sqlx::query("INSERT INTO token (hash, \"user\", name, created, expires, scopes, prefix) SELECT $1,$2,$3,$4,$5,$6,$7 WHERE NOT EXISTS(SELECT * FROM token WHERE \"user\" = $2 AND name = $3)")
+        .bind(hash(&token))
+        .bind(username)
+        .bind(name)
+        .bind(now())
+        .bind(expires)
+        .bind(Scope::join(scopes))
+        .bind(prefix)
+        .execute(executor)
+        .await?
+        .rows_affected();
+
+    Ok(if rows == 1 { Some(token) } else { None })
+}
+
+pub async fn find<'e, E>(executor: E, username: &str, name: &str) -> sqlx::Result<Option<db::Token>>
+where
+    E: Executor<'e, Database = Any>,
+{
+    sqlx::query_as::<_, db::Token>(&format!(
+        "{} WHERE \"user\" = $1 AND name = $2",
+        SELECT_TOKEN
+    ))
+    .bind(username)
+    .bind(name)
+    .fetch_optional(executor)
+    .await
+}
+
+pub async fn list<'e, E>(executor: E, username: &str) -> sqlx::Result<Vec<db::Token>>
+where
+    E: Executor<'e, Database = Any>,
+{
+    sqlx::query_as::<_, db::Token>(&format!(
+        "{} WHERE \"user\" = $1 ORDER BY name",
+        SELECT_TOKEN
+    ))
+    .bind(username)
+    .fetch_all(executor)
+    .await
+}
+
+/// Deletes a token, returns whether it existed.
+pub async fn revoke<'e, E>(executor: E, username: &str, name: &str) -> sqlx::Result<bool>
+where
+    E: Executor<'e, Database = Any>,
+{
+    let rows = sqlx::query("DELETE FROM token WHERE \"user\" = $1 AND name = $2")
+        .bind(username)
+        .bind(name)
+        .execute(executor)
+        .await?
+        .rows_affected();
+
+    Ok(rows == 1)
+}
+
+/// Looks up what an unexpired token grants and records that it was used.
+pub async fn verify(db_pool: &AnyPool, token: &str) -> sqlx::Result<Option<db::Grant>> {
+    let hash = hash(token);
+    let now = now();
+
+    let grant = sqlx::query_as::<_, db::Grant>(
+        "SELECT t.\"user\", u.is_admin, t.scopes, t.prefix FROM token t INNER JOIN \"user\" u ON u.username = t.\"user\" WHERE t.hash = $1 AND (t.expires IS NULL OR t.expires > $2)",
+    )
+    .bind(&hash)
+    .bind(&now)
+    .fetch_optional(db_pool)
+    .await?;
+
+    if grant.is_some() {
+        sqlx::query("UPDATE token SET last_used = $1 WHERE hash = $2")
+            .bind(&now)
+            .bind(&hash)
+            .execute(db_pool)
+            .await?;
+    }
+
+    Ok(grant)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{generate, hash, TOKEN_PREFIX};
+
+    #[test]
+    fn generated_tokens() {
+        let a = generate();
+        let b = generate();
+
+        assert!(a.starts_with(TOKEN_PREFIX));
+        assert_ne!(a, b);
+        assert_eq!(64, hash(&a).len());
+        assert_eq!(hash(&a), hash(&a.clone()));
+        assert_ne!(hash(&a), hash(&b));
+    }
+}
@@ -0,0 +1,245 @@
+use chrono::{Duration as ChronoDuration, Utc};
+use log::{info, warn};
+use sqlx::AnyPool;
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+
+use crate::model::TIMESTAMP_FORMAT;
+
+/// Number of hits that can be queued before new ones are dropped.
+const QUEUE_SIZE: usize = 4096;
+/// Longest referrer and user agent that is stored, anything beyond is cut off.
+const MAX_HEADER_LEN: usize = 512;
+/// How often clicks older than the retention period are removed.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Counts how often redirects are used and logs every click. Hits are collected in
+/// memory and written to the database in batches, so recording one never waits for
+/// the database.
+#[derive(Clone)]
+pub struct Tracker {
+    tx: mpsc::Sender<Hit>,
+}
+
+/// Who followed a redirect.
+#[derive(Debug, Default)]
+pub struct Visitor {
+    pub referrer: Option<String>,
+    pub user_agent: Option<String>,
+    pub addr: Option<SocketAddr>,
+}
+
+#[derive(Debug)]
+struct Hit {
+    path: String,
+    timestamp: String,
+    referrer: Option<String>,
+    user_agent: Option<String>,
+    ip: Option<String>,
+}
+
+#[derive(Debug)]
+struct Hits {
+    count: i64,
+    last: String,
+}
+
+impl Tracker {
+    /// Starts the background task writing hits every `interval`. The task flushes the
+    /// remaining hits and stops once every [`Tracker`] has been dropped.
+    pub fn new(db_pool: AnyPool, interval: Duration) -> (Self, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+        let handle = tokio::spawn(run(db_pool, rx, interval));
+
+        (Tracker { tx }, handle)
+    }
+
+    pub fn hit(&self, path: &str, visitor: Visitor) {
+        let hit = Hit {
+            path: path.to_string(),
+            timestamp: Utc::now().format(TIMESTAMP_FORMAT).to_string(),
+            referrer: visitor.referrer.map(truncate),
+            user_agent: visitor.user_agent.map(truncate),
+            ip: visitor.addr.map(|addr| anonymize(addr.ip()).to_string()),
+        };
+
+        if self.tx.try_send(hit).is_err() {
+            warn!("hit queue is full, dropping hit for {}", path);
+        }
+    }
+}
+
+async fn run(db_pool: AnyPool, mut rx: mpsc::Receiver<Hit>, interval: Duration) {
+    let mut hits = Vec::new();
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        tokio::select! {
+            hit = rx.recv() => match hit {
+                Some(hit) => hits.push(hit),
+                None => break,
+            },
+            _ = ticker.tick() => flush(&db_pool, &mut hits).await,
+        }
+    }
+
+    flush(&db_pool, &mut hits).await;
+}
+
+async fn flush(db_pool: &AnyPool, hits: &mut Vec<Hit>) {
+    let mut counts = HashMap::new();
+
+    for hit in hits.drain(..) {
+        let result = sqlx::query(
+            "INSERT INTO click (path, created, referrer, user_agent, ip) VALUES ($1,$2,$3,$4,$5)",
+        )
+        .bind(&hit.path)
+        .bind(&hit.timestamp)
+        .bind(hit.referrer)
+        .bind(hit.user_agent)
+        .bind(hit.ip)
+        .execute(db_pool)
+        .await;
+
+        if let Err(e) = result {
+            warn!("failed to write click for {}: {}", hit.path, e);
+        }
+
+        let counts = counts.entry(hit.path).or_insert(Hits {
+            count: 0,
+            last: String::new(),
+        });
+        counts.count += 1;
+        counts.last = hit.timestamp;
+    }
+
+    for (path, hits) in counts {
+        let result =
+            sqlx::query("UPDATE redirect SET hits = hits + $1, last_accessed = $2 WHERE path = $3")
+                .bind(hits.count)
+                .bind(hits.last)
+                .bind(&path)
+                .execute(db_pool)
+                .await;
+
+        if let Err(e) = result {
+            warn!("failed to write hits for {}: {}", path, e);
+        }
+    }
+}
+
+/// Removes clicks older than `retention_days` once an hour, runs until aborted.
+pub async fn prune(db_pool: AnyPool, retention_days: u64) {
+    let mut ticker = tokio::time::interval(PRUNE_INTERVAL);
+
+    loop {
+        ticker.tick().await;
+
+        let cutoff = Utc::now() - ChronoDuration::days(retention_days as i64);
+        let result = sqlx::query("DELETE FROM click WHERE created < $1")
+            .bind(cutoff.naive_utc())
+            .execute(&db_pool)
+            .await;
+
+        match result {
+            Ok(done) => info!("pruned {} clicks", done.rows_affected()),
+            Err(e) => warn!("failed to prune clicks: {}", e),
+        }
+    }
+}
+
+/// Drops the host part of an address, only the network of the client is stored.
+fn anonymize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            IpAddr::V4(Ipv4Addr::new(a, b, c, 0))
+        }
+        IpAddr::V6(ip) => {
+            let [a, b, c, ..] = ip.segments();
+            IpAddr::V6(Ipv6Addr::new(a, b, c, 0, 0, 0, 0, 0))
+        }
+    }
+}
+
+fn truncate(mut s: String) -> String {
+    if s.len() > MAX_HEADER_LEN {
+        let mut end = MAX_HEADER_LEN;
+        while !s.is_char_boundary(end) {
+            end -= 1;
+        }
+        s.truncate(end);
+    }
+    s
+}
+
+#[cfg(test)]
+mod test {
+    use super::{anonymize, Tracker, Visitor};
+    use std::{net::IpAddr, time::Duration};
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 1)]
+    async fn flush_on_drop() {
+        let db_pool = sqlx::any::AnyPoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+
+        sqlx::migrate!().run(&db_pool).await.unwrap();
+
+        sqlx::query("INSERT INTO \"user\" (username, pw_hash) VALUES ('test', '')")
+            .execute(&db_pool)
+            .await
+            .unwrap();
+        sqlx::query("INSERT INTO redirect (\"user\", path, url) VALUES ('test', 'blub', 'https://example.com/')")
+            .execute(&db_pool)
+            .await
+            .unwrap();
+
+        let (tracker, handle) = Tracker::new(db_pool.clone(), Duration::from_secs(3600));
+
+        tracker.hit("blub", Visitor::default());
+        tracker.hit(
+            "blub",
+            Visitor {
+                referrer: Some("https://example.org/".to_string()),
+                user_agent: Some("curl/7.74.0".to_string()),
+                addr: Some(([192, 168, 0, 42], 4321).into()),
+            },
+        );
+        drop(tracker);
+        handle.await.unwrap();
+
+        let (hits, last_accessed): (i64, Option<String>) =
+            sqlx::query_as("SELECT hits, last_accessed FROM redirect WHERE path = 'blub'")
+                .fetch_one(&db_pool)
+                .await
+                .unwrap();
+
+        assert_eq!(2, hits);
+        assert!(last_accessed.is_some());
+
+        let ips: Vec<(Option<String>,)> = sqlx::query_as("SELECT ip FROM click ORDER BY ip")
+            .fetch_all(&db_pool)
+            .await
+            .unwrap();
+
+        assert_eq!(vec![(None,), (Some("192.168.0.0".to_string()),)], ips);
+    }
+
+    #[test]
+    fn anonymize_ip() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        assert_eq!(ip("10.1.2.0"), anonymize(ip("10.1.2.3")));
+        assert_eq!(
+            ip("2001:db8:85a3::"),
+            anonymize(ip("2001:db8:85a3:8d3:1319:8a2e:370:7344"))
+        );
+    }
+}